@@ -1,14 +1,22 @@
-use super::models::Configuration;
+use super::models::{AirportTexts, Configuration, RenderMetadata};
 use jfs::Store;
+use std::collections::HashMap;
+use std::sync::Mutex;
 
 pub struct Fetcher {
   db: Store,
+  /// Metadata of the last render of each configuration, kept in memory.
+  render_metadata: Mutex<HashMap<String, RenderMetadata>>,
+  /// Airport subscriptions of the last render of each configuration.
+  airport_texts: Mutex<HashMap<String, AirportTexts>>,
 }
 
 impl Fetcher {
   pub fn new(path: &str) -> Fetcher {
     Fetcher {
       db: Store::new(path).unwrap(),
+      render_metadata: Mutex::new(HashMap::new()),
+      airport_texts: Mutex::new(HashMap::new()),
     }
   }
 
@@ -24,8 +32,42 @@ impl Fetcher {
   }
 
   pub fn delete_configuration(&self, name: &str) {
+    self.render_metadata.lock().unwrap().remove(name);
+    self.airport_texts.lock().unwrap().remove(name);
     self.db.delete(name).unwrap()
   }
+
+  pub fn save_render_metadata(&self, name: &str, metadata: &RenderMetadata) {
+    self
+      .render_metadata
+      .lock()
+      .unwrap()
+      .insert(String::from(name), metadata.clone());
+  }
+
+  pub fn get_render_metadata(&self, name: &str) -> Option<RenderMetadata> {
+    self.render_metadata.lock().unwrap().get(name).cloned()
+  }
+
+  pub fn save_airport_texts(&self, name: &str, texts: &AirportTexts) {
+    self
+      .airport_texts
+      .lock()
+      .unwrap()
+      .insert(String::from(name), texts.clone());
+  }
+
+  /// The body the airport's subscription was last fetched with, `Some(None)`
+  /// if the download failed and `None` if it was never fetched.
+  pub fn get_airport_text(&self, name: &str, airport_id: &str) -> Option<Option<String>> {
+    self
+      .airport_texts
+      .lock()
+      .unwrap()
+      .get(name)?
+      .get(airport_id)
+      .cloned()
+  }
 }
 
 #[cfg(test)]
@@ -51,16 +93,47 @@ mod test {
       "airport_2",
       "airport_2_name",
       "https://gist.githubusercontent.com/nearsyh/45695b3332f02609c71a1a084dbfb5bf/raw/67c0c6b1ae2c5a8f044a5f7ea10d009c990c5469/surge_config_airport_2"));
-    configuration.upsert_group_configuration(GroupConfiguration::new(
-      "group_1",
-      "group_1_name",
-      "Media",
-    ));
+    configuration
+      .upsert_group_configuration(GroupConfiguration::new(
+        "group_1",
+        "group_1_name",
+        "Media",
+      ))
+      .unwrap();
     fetcher.save_configuration(&configuration);
     let saved = fetcher.get_configuration(configuration.get_name()).unwrap();
     assert_eq!(configuration, saved);
   }
 
+  #[test]
+  pub fn render_metadata_should_be_kept_per_configuration() {
+    let fetcher = Fetcher::new("data");
+    assert!(fetcher.get_render_metadata("rendered").is_none());
+    fetcher.save_render_metadata("rendered", &RenderMetadata::default());
+    assert_eq!(
+      fetcher.get_render_metadata("rendered"),
+      Some(RenderMetadata::default())
+    );
+    assert!(fetcher.get_render_metadata("other").is_none());
+  }
+
+  #[test]
+  pub fn airport_texts_should_be_kept_per_configuration() {
+    let fetcher = Fetcher::new("data");
+    assert!(fetcher.get_airport_text("fetched", "airport_1").is_none());
+    let mut texts = AirportTexts::new();
+    texts.insert(String::from("airport_1"), Some(String::from("[Proxy]\n")));
+    texts.insert(String::from("airport_2"), None);
+    fetcher.save_airport_texts("fetched", &texts);
+    assert_eq!(
+      fetcher.get_airport_text("fetched", "airport_1"),
+      Some(Some(String::from("[Proxy]\n")))
+    );
+    assert_eq!(fetcher.get_airport_text("fetched", "airport_2"), Some(None));
+    assert!(fetcher.get_airport_text("fetched", "airport_3").is_none());
+    assert!(fetcher.get_airport_text("other", "airport_1").is_none());
+  }
+
   #[test]
   pub fn get_non_exist_configuration_should_work() {
     let fetcher = Fetcher::new("data");
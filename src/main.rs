@@ -6,8 +6,13 @@ use serde::{Deserialize, Serialize};
 extern crate lazy_static;
 
 use actix_cors::Cors;
+use actix_web::error::InternalError;
 use actix_web::{delete, get, post, put, web, App, Error, HttpResponse, HttpServer, Result};
-use models::{AirportConfiguration, Configuration, GroupConfiguration};
+use models::{
+    AirportConfiguration, BuiltinGroupConfiguration, ClashConfiguration, Configuration,
+    GroupConfiguration, GroupRendering, Mitm, NameCollision, ProxyDeduplication,
+    PseudoNodeFilter, RenameRule, ScriptConfiguration, SurgeConfiguration,
+};
 
 lazy_static! {
     static ref FETCHER: fetcher::Fetcher = fetcher::Fetcher::new("data");
@@ -34,10 +39,13 @@ async fn update_configuration(
     config: web::Json<Configuration>,
 ) -> Result<HttpResponse, Error> {
     match FETCHER.get_configuration(&configuration_id) {
-        Some(_) => {
-            FETCHER.save_configuration(&config);
-            Ok(HttpResponse::Ok().json(config.into_inner()))
-        }
+        Some(_) => match config.validate() {
+            Ok(()) => {
+                FETCHER.save_configuration(&config);
+                Ok(HttpResponse::Ok().json(config.into_inner()))
+            }
+            Err(reason) => Ok(HttpResponse::BadRequest().json(reason)),
+        },
         None => Ok(HttpResponse::NotFound().json("Configuration Not Found")),
     }
 }
@@ -75,13 +83,163 @@ async fn upsert_airport_configuration(
     }
 }
 
+#[get("/api/v1/configurations/{config_id}/airports/{airport_id}")]
+async fn get_airport_status(path: web::Path<(String, String)>) -> Result<HttpResponse, Error> {
+    if let Some(configuration) = FETCHER.get_configuration(&path.0) {
+        if !configuration.has_airport(&path.1) {
+            return Ok(HttpResponse::NotFound().json("Airport Not Found"));
+        }
+        match FETCHER.get_airport_text(&path.0, &path.1) {
+            Some(text) => Ok(HttpResponse::Ok()
+                .json(configuration.airport_status(&path.1, text.as_deref()))),
+            None => Ok(HttpResponse::NotFound().json("Airport has not been fetched yet")),
+        }
+    } else {
+        Ok(HttpResponse::NotFound().json("Configuration Not Found"))
+    }
+}
+
+#[get("/api/v1/configurations/{config_id}/airports/{airport_id}/diagnostics")]
+async fn get_airport_diagnostics(path: web::Path<(String, String)>) -> Result<HttpResponse, Error> {
+    if let Some(configuration) = FETCHER.get_configuration(&path.0) {
+        if !configuration.has_airport(&path.1) {
+            return Ok(HttpResponse::NotFound().json("Airport Not Found"));
+        }
+        match FETCHER.get_airport_text(&path.0, &path.1) {
+            Some(text) => Ok(HttpResponse::Ok()
+                .json(configuration.airport_diagnostics(&path.1, text.as_deref()))),
+            None => Ok(HttpResponse::NotFound().json("Airport has not been fetched yet")),
+        }
+    } else {
+        Ok(HttpResponse::NotFound().json("Configuration Not Found"))
+    }
+}
+
 #[post("/api/v1/configurations/{config_id}/groups")]
 async fn upsert_group_configuration(
     path: web::Path<String>,
     group: web::Json<GroupConfiguration>,
 ) -> Result<HttpResponse, Error> {
     if let Some(mut configuration) = FETCHER.get_configuration(&path) {
-        configuration.upsert_group_configuration(group.into_inner());
+        match configuration.upsert_group_configuration(group.into_inner()) {
+            Ok(()) => {
+                FETCHER.save_configuration(&configuration);
+                Ok(HttpResponse::Ok().json(configuration))
+            }
+            Err(reason) => Ok(HttpResponse::BadRequest().json(reason)),
+        }
+    } else {
+        Ok(HttpResponse::NotFound().json("Configuration Not Found"))
+    }
+}
+
+#[post("/api/v1/configurations/{config_id}/airport_order")]
+async fn reorder_airports(
+    path: web::Path<String>,
+    airport_ids: web::Json<Vec<String>>,
+) -> Result<HttpResponse, Error> {
+    if let Some(mut configuration) = FETCHER.get_configuration(&path) {
+        match configuration.reorder_airports(&airport_ids) {
+            Ok(()) => {
+                FETCHER.save_configuration(&configuration);
+                Ok(HttpResponse::Ok().json(configuration))
+            }
+            Err(reason) => Ok(HttpResponse::BadRequest().json(reason)),
+        }
+    } else {
+        Ok(HttpResponse::NotFound().json("Configuration Not Found"))
+    }
+}
+
+#[post("/api/v1/configurations/{config_id}/group_order")]
+async fn reorder_groups(
+    path: web::Path<String>,
+    group_ids: web::Json<Vec<String>>,
+) -> Result<HttpResponse, Error> {
+    if let Some(mut configuration) = FETCHER.get_configuration(&path) {
+        match configuration.reorder_groups(&group_ids) {
+            Ok(()) => {
+                FETCHER.save_configuration(&configuration);
+                Ok(HttpResponse::Ok().json(configuration))
+            }
+            Err(reason) => Ok(HttpResponse::BadRequest().json(reason)),
+        }
+    } else {
+        Ok(HttpResponse::NotFound().json("Configuration Not Found"))
+    }
+}
+
+#[post("/api/v1/configurations/{config_id}/name_collision")]
+async fn update_name_collision_configuration(
+    path: web::Path<String>,
+    name_collision: web::Json<NameCollision>,
+) -> Result<HttpResponse, Error> {
+    if let Some(mut configuration) = FETCHER.get_configuration(&path) {
+        configuration.update_name_collision(name_collision.into_inner());
+        FETCHER.save_configuration(&configuration);
+        Ok(HttpResponse::Ok().json(configuration))
+    } else {
+        Ok(HttpResponse::NotFound().json("Configuration Not Found"))
+    }
+}
+
+#[post("/api/v1/configurations/{config_id}/proxy_deduplication")]
+async fn update_proxy_deduplication_configuration(
+    path: web::Path<String>,
+    proxy_deduplication: web::Json<ProxyDeduplication>,
+) -> Result<HttpResponse, Error> {
+    if let Some(mut configuration) = FETCHER.get_configuration(&path) {
+        configuration.update_proxy_deduplication(proxy_deduplication.into_inner());
+        FETCHER.save_configuration(&configuration);
+        Ok(HttpResponse::Ok().json(configuration))
+    } else {
+        Ok(HttpResponse::NotFound().json("Configuration Not Found"))
+    }
+}
+
+#[post("/api/v1/configurations/{config_id}/rename_rules")]
+async fn update_rename_rules_configuration(
+    path: web::Path<String>,
+    rename_rules: web::Json<Vec<RenameRule>>,
+) -> Result<HttpResponse, Error> {
+    if let Some(mut configuration) = FETCHER.get_configuration(&path) {
+        match configuration.update_rename_rules(rename_rules.into_inner()) {
+            Ok(()) => {
+                FETCHER.save_configuration(&configuration);
+                Ok(HttpResponse::Ok().json(configuration))
+            }
+            Err(reason) => Ok(HttpResponse::BadRequest().json(reason)),
+        }
+    } else {
+        Ok(HttpResponse::NotFound().json("Configuration Not Found"))
+    }
+}
+
+#[post("/api/v1/configurations/{config_id}/pseudo_node_filter")]
+async fn update_pseudo_node_filter_configuration(
+    path: web::Path<String>,
+    pseudo_node_filter: web::Json<PseudoNodeFilter>,
+) -> Result<HttpResponse, Error> {
+    if let Some(mut configuration) = FETCHER.get_configuration(&path) {
+        match configuration.update_pseudo_node_filter(pseudo_node_filter.into_inner()) {
+            Ok(()) => {
+                FETCHER.save_configuration(&configuration);
+                Ok(HttpResponse::Ok().json(configuration))
+            }
+            Err(reason) => Ok(HttpResponse::BadRequest().json(reason)),
+        }
+    } else {
+        Ok(HttpResponse::NotFound().json("Configuration Not Found"))
+    }
+}
+
+#[post("/api/v1/configurations/{config_id}/group_rendering")]
+async fn update_group_rendering_configuration(
+    path: web::Path<String>,
+    group_rendering: web::Json<GroupRendering>,
+) -> Result<HttpResponse, Error> {
+    if let Some(mut configuration) = FETCHER.get_configuration(&path) {
+        configuration.update_group_rendering(group_rendering.into_inner());
         FETCHER.save_configuration(&configuration);
         Ok(HttpResponse::Ok().json(configuration))
     } else {
@@ -89,6 +247,42 @@ async fn upsert_group_configuration(
     }
 }
 
+#[post("/api/v1/configurations/{config_id}/auto_group")]
+async fn update_auto_group_configuration(
+    path: web::Path<String>,
+    group: web::Json<BuiltinGroupConfiguration>,
+) -> Result<HttpResponse, Error> {
+    if let Some(mut configuration) = FETCHER.get_configuration(&path) {
+        match configuration.update_auto_group(group.into_inner()) {
+            Ok(()) => {
+                FETCHER.save_configuration(&configuration);
+                Ok(HttpResponse::Ok().json(configuration))
+            }
+            Err(reason) => Ok(HttpResponse::BadRequest().json(reason)),
+        }
+    } else {
+        Ok(HttpResponse::NotFound().json("Configuration Not Found"))
+    }
+}
+
+#[post("/api/v1/configurations/{config_id}/proxy_group")]
+async fn update_proxy_group_configuration(
+    path: web::Path<String>,
+    group: web::Json<BuiltinGroupConfiguration>,
+) -> Result<HttpResponse, Error> {
+    if let Some(mut configuration) = FETCHER.get_configuration(&path) {
+        match configuration.update_proxy_group(group.into_inner()) {
+            Ok(()) => {
+                FETCHER.save_configuration(&configuration);
+                Ok(HttpResponse::Ok().json(configuration))
+            }
+            Err(reason) => Ok(HttpResponse::BadRequest().json(reason)),
+        }
+    } else {
+        Ok(HttpResponse::NotFound().json("Configuration Not Found"))
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct TextConfiguration {
     text: String,
@@ -136,13 +330,220 @@ async fn update_url_rewrites_configuration(
     }
 }
 
+#[post("/api/v1/configurations/{config_id}/header_rewrites")]
+async fn update_header_rewrites_configuration(
+    path: web::Path<String>,
+    text: web::Json<TextConfiguration>,
+) -> Result<HttpResponse, Error> {
+    if let Some(mut configuration) = FETCHER.get_configuration(&path) {
+        match configuration.update_header_rewrites(&text.text) {
+            Ok(()) => {
+                FETCHER.save_configuration(&configuration);
+                Ok(HttpResponse::Ok().json(configuration))
+            }
+            Err(reason) => Ok(HttpResponse::BadRequest().json(reason)),
+        }
+    } else {
+        Ok(HttpResponse::NotFound().json("Configuration Not Found"))
+    }
+}
+
+#[post("/api/v1/configurations/{config_id}/body_rewrites")]
+async fn update_body_rewrites_configuration(
+    path: web::Path<String>,
+    text: web::Json<TextConfiguration>,
+) -> Result<HttpResponse, Error> {
+    if let Some(mut configuration) = FETCHER.get_configuration(&path) {
+        match configuration.update_body_rewrites(&text.text) {
+            Ok(()) => {
+                FETCHER.save_configuration(&configuration);
+                Ok(HttpResponse::Ok().json(configuration))
+            }
+            Err(reason) => Ok(HttpResponse::BadRequest().json(reason)),
+        }
+    } else {
+        Ok(HttpResponse::NotFound().json("Configuration Not Found"))
+    }
+}
+
+#[post("/api/v1/configurations/{config_id}/map_locals")]
+async fn update_map_locals_configuration(
+    path: web::Path<String>,
+    text: web::Json<TextConfiguration>,
+) -> Result<HttpResponse, Error> {
+    if let Some(mut configuration) = FETCHER.get_configuration(&path) {
+        match configuration.update_map_locals(&text.text) {
+            Ok(()) => {
+                FETCHER.save_configuration(&configuration);
+                Ok(HttpResponse::Ok().json(configuration))
+            }
+            Err(reason) => Ok(HttpResponse::BadRequest().json(reason)),
+        }
+    } else {
+        Ok(HttpResponse::NotFound().json("Configuration Not Found"))
+    }
+}
+
+#[post("/api/v1/configurations/{config_id}/hosts")]
+async fn update_hosts_configuration(
+    path: web::Path<String>,
+    text: web::Json<TextConfiguration>,
+) -> Result<HttpResponse, Error> {
+    if let Some(mut configuration) = FETCHER.get_configuration(&path) {
+        match configuration.update_hosts(&text.text) {
+            Ok(()) => {
+                FETCHER.save_configuration(&configuration);
+                Ok(HttpResponse::Ok().json(configuration))
+            }
+            Err(reason) => Ok(HttpResponse::BadRequest().json(reason)),
+        }
+    } else {
+        Ok(HttpResponse::NotFound().json("Configuration Not Found"))
+    }
+}
+
+#[post("/api/v1/configurations/{config_id}/wireguards")]
+async fn update_wireguards_configuration(
+    path: web::Path<String>,
+    text: web::Json<TextConfiguration>,
+) -> Result<HttpResponse, Error> {
+    if let Some(mut configuration) = FETCHER.get_configuration(&path) {
+        match configuration.update_wireguards(&text.text) {
+            Ok(()) => {
+                FETCHER.save_configuration(&configuration);
+                Ok(HttpResponse::Ok().json(configuration))
+            }
+            Err(reason) => Ok(HttpResponse::BadRequest().json(reason)),
+        }
+    } else {
+        Ok(HttpResponse::NotFound().json("Configuration Not Found"))
+    }
+}
+
+#[post("/api/v1/configurations/{config_id}/mitm")]
+async fn update_mitm_configuration(
+    path: web::Path<String>,
+    mitm: web::Json<Mitm>,
+) -> Result<HttpResponse, Error> {
+    if let Some(mut configuration) = FETCHER.get_configuration(&path) {
+        match configuration.update_mitm(mitm.into_inner()) {
+            Ok(()) => {
+                FETCHER.save_configuration(&configuration);
+                Ok(HttpResponse::Ok().json(configuration))
+            }
+            Err(reason) => Ok(HttpResponse::BadRequest().json(reason)),
+        }
+    } else {
+        Ok(HttpResponse::NotFound().json("Configuration Not Found"))
+    }
+}
+
+#[post("/api/v1/configurations/{config_id}/scripts")]
+async fn upsert_script_configuration(
+    path: web::Path<String>,
+    script: web::Json<ScriptConfiguration>,
+) -> Result<HttpResponse, Error> {
+    if let Some(mut configuration) = FETCHER.get_configuration(&path) {
+        match configuration.upsert_script(script.into_inner()) {
+            Ok(()) => {
+                FETCHER.save_configuration(&configuration);
+                Ok(HttpResponse::Ok().json(configuration))
+            }
+            Err(reason) => Ok(HttpResponse::BadRequest().json(reason)),
+        }
+    } else {
+        Ok(HttpResponse::NotFound().json("Configuration Not Found"))
+    }
+}
+
+#[get("/api/v1/configurations/{config_id}/scripts/{file_name}")]
+async fn get_script(path: web::Path<(String, String)>) -> Result<HttpResponse, Error> {
+    if let Some(configuration) = FETCHER.get_configuration(&path.0) {
+        let script = path
+            .1
+            .strip_suffix(".js")
+            .and_then(|name| configuration.get_script(name));
+        match script {
+            Some(script) => Ok(HttpResponse::Ok()
+                .content_type("application/javascript")
+                .body(script.get_body().to_string())),
+            None => Ok(HttpResponse::NotFound().json("Script Not Found")),
+        }
+    } else {
+        Ok(HttpResponse::NotFound().json("Configuration Not Found"))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct SurgeQuery {
+    group_rendering: Option<GroupRendering>,
+}
+
+/// Fetches the airports and renders the configuration, keeping what was
+/// fetched and the render metadata for the endpoints that report on them.
+async fn render(
+    configuration: &Configuration,
+    group_rendering: GroupRendering,
+) -> Result<SurgeConfiguration, String> {
+    configuration.validate()?;
+    let texts = configuration.fetch_airport_texts().await;
+    FETCHER.save_airport_texts(configuration.get_name(), &texts);
+    let surge_configuration = configuration.render_airport_texts(&texts, group_rendering)?;
+    FETCHER.save_render_metadata(configuration.get_name(), surge_configuration.get_metadata());
+    Ok(surge_configuration)
+}
+
 #[get("/api/v1/configurations/{config_id}/surge")]
-async fn get_surge_configurationpath(path: web::Path<String>) -> Result<HttpResponse, Error> {
+async fn get_surge_configurationpath(
+    path: web::Path<String>,
+    query: web::Query<SurgeQuery>,
+) -> Result<HttpResponse, Error> {
+    if let Some(configuration) = FETCHER.get_configuration(&path) {
+        let group_rendering = query
+            .group_rendering
+            .unwrap_or_else(|| configuration.get_group_rendering());
+        match render(&configuration, group_rendering).await {
+            Ok(surge_configuration) => {
+                Ok(HttpResponse::Ok().body(surge_configuration.to_string()))
+            }
+            Err(reason) => Ok(HttpResponse::BadRequest().json(format!(
+                "Fail to generation surge configuration: {}",
+                reason
+            ))),
+        }
+    } else {
+        Ok(HttpResponse::NotFound().json("Configuration Not Found"))
+    }
+}
+
+/// Returns the metadata of the last successful Surge or Clash render of the
+/// configuration, without rendering it again.
+#[get("/api/v1/configurations/{config_id}/metadata")]
+async fn get_render_metadata(path: web::Path<String>) -> Result<HttpResponse, Error> {
+    if FETCHER.get_configuration(&path).is_some() {
+        match FETCHER.get_render_metadata(&path) {
+            Some(metadata) => Ok(HttpResponse::Ok().json(metadata)),
+            None => Ok(HttpResponse::NotFound().json("Configuration has not been rendered yet")),
+        }
+    } else {
+        Ok(HttpResponse::NotFound().json("Configuration Not Found"))
+    }
+}
+
+#[get("/api/v1/configurations/{config_id}/clash")]
+async fn get_clash_configuration(path: web::Path<String>) -> Result<HttpResponse, Error> {
     if let Some(configuration) = FETCHER.get_configuration(&path) {
-        if let Some(surge_configuration) = configuration.fetch_surge_configuration().await {
-            Ok(HttpResponse::Ok().body(surge_configuration.to_string()))
-        } else {
-            Ok(HttpResponse::BadRequest().json("Fail to generation surge configuration"))
+        // Clash knows nothing of Surge's group options.
+        match render(&configuration, GroupRendering::Expand).await {
+            Ok(surge_configuration) => {
+                let clash_configuration =
+                    ClashConfiguration::from_surge_configuration(&surge_configuration);
+                Ok(HttpResponse::Ok().body(clash_configuration.to_string()))
+            }
+            Err(reason) => Ok(HttpResponse::BadRequest().json(format!(
+                "Fail to generation clash configuration: {}",
+                reason
+            ))),
         }
     } else {
         Ok(HttpResponse::NotFound().json("Configuration Not Found"))
@@ -162,16 +563,42 @@ async fn main() -> std::io::Result<()> {
     let init_closure = || {
         App::new()
             .wrap(Cors::new().send_wildcard().finish())
+            // Malformed bodies get a 400 with the reason, like failed validations.
+            .app_data(web::JsonConfig::default().error_handler(|error, _| {
+                let reason = error.to_string();
+                InternalError::from_response(error, HttpResponse::BadRequest().json(reason)).into()
+            }))
             .service(health)
             .service(create_configuration)
             .service(get_configuration)
             .service(update_configuration)
             .service(upsert_airport_configuration)
+            .service(get_airport_status)
+            .service(get_airport_diagnostics)
             .service(upsert_group_configuration)
+            .service(reorder_airports)
+            .service(reorder_groups)
+            .service(update_group_rendering_configuration)
+            .service(update_name_collision_configuration)
+            .service(update_proxy_deduplication_configuration)
+            .service(update_rename_rules_configuration)
+            .service(update_pseudo_node_filter_configuration)
+            .service(update_auto_group_configuration)
+            .service(update_proxy_group_configuration)
             .service(update_rules_configuration)
             .service(update_generals_configuration)
             .service(update_url_rewrites_configuration)
+            .service(update_header_rewrites_configuration)
+            .service(update_body_rewrites_configuration)
+            .service(update_map_locals_configuration)
+            .service(update_hosts_configuration)
+            .service(update_wireguards_configuration)
+            .service(update_mitm_configuration)
+            .service(upsert_script_configuration)
+            .service(get_script)
             .service(get_surge_configurationpath)
+            .service(get_render_metadata)
+            .service(get_clash_configuration)
     };
     HttpServer::new(init_closure)
         .bind("0.0.0.0:8080")?
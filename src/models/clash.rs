@@ -0,0 +1,694 @@
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+use std::collections::HashSet;
+use std::fmt;
+
+use super::protocol::ProxyProtocol;
+use super::surge::{
+  Proxy, ProxyGroup, ProxyGroupType, SurgeConfiguration, DEFAULT_TEST_INTERVAL, DEFAULT_TEST_URL,
+};
+
+const CLASH_RULE_TYPES: &[&str] = &[
+  "DOMAIN",
+  "DOMAIN-SUFFIX",
+  "DOMAIN-KEYWORD",
+  "GEOIP",
+  "IP-CIDR",
+  "IP-CIDR6",
+  "SRC-IP-CIDR",
+  "SRC-PORT",
+  "DST-PORT",
+  "PROCESS-NAME",
+  "MATCH",
+];
+
+fn insert<V: Into<Value>>(mapping: &mut Mapping, key: &str, value: V) {
+  mapping.insert(Value::from(key), value.into());
+}
+
+fn bool_parameter(proxy: &Proxy, name: &str) -> Option<bool> {
+  proxy
+    .get_parameter(name)
+    .map(|value| value == "true" || value == "1")
+}
+
+fn insert_bool_parameter(mapping: &mut Mapping, proxy: &Proxy, surge_name: &str, clash_name: &str) {
+  if let Some(value) = bool_parameter(proxy, surge_name) {
+    insert(mapping, clash_name, value);
+  }
+}
+
+fn insert_str_parameter(mapping: &mut Mapping, proxy: &Proxy, surge_name: &str, clash_name: &str) {
+  if let Some(value) = proxy.get_parameter(surge_name) {
+    insert(mapping, clash_name, value);
+  }
+}
+
+/// Surge encodes websocket headers as `Name:Value|Name:Value`.
+fn ws_headers(headers: &str) -> Mapping {
+  let mut ret = Mapping::new();
+  for header in headers.split('|') {
+    let parts: Vec<_> = header.splitn(2, ':').collect();
+    if let [name, value] = &parts[..] {
+      insert(&mut ret, name.trim(), value.trim());
+    }
+  }
+  ret
+}
+
+fn insert_ws_options(mapping: &mut Mapping, proxy: &Proxy) {
+  if bool_parameter(proxy, "ws") != Some(true) {
+    return;
+  }
+  insert(mapping, "network", "ws");
+  let mut ws_opts = Mapping::new();
+  if let Some(path) = proxy.get_parameter("ws-path") {
+    insert(&mut ws_opts, "path", path);
+  }
+  if let Some(headers) = proxy.get_parameter("ws-headers") {
+    insert(&mut ws_opts, "headers", ws_headers(headers));
+  }
+  insert(mapping, "ws-opts", ws_opts);
+}
+
+fn insert_credentials(mapping: &mut Mapping, proxy: &Proxy) {
+  if let Some(username) = proxy.get_username() {
+    insert(mapping, "username", username);
+  }
+  if let Some(password) = proxy.get_password() {
+    insert(mapping, "password", password);
+  }
+}
+
+fn clash_proxy(proxy: &Proxy) -> Option<Mapping> {
+  let mut ret = Mapping::new();
+  insert(&mut ret, "name", proxy.get_name());
+  let proto = match proxy.get_proto() {
+    "https" => "http",
+    "socks5-tls" => "socks5",
+    proto => proto,
+  };
+  insert(&mut ret, "type", proto);
+  insert(&mut ret, "server", proxy.get_host());
+  insert(&mut ret, "port", u64::from(proxy.get_port()));
+  match proxy.get_proto() {
+    "ss" => {
+      insert(&mut ret, "cipher", proxy.get_parameter("encrypt-method")?);
+      insert(&mut ret, "password", proxy.get_parameter("password")?);
+      if let Some(obfs) = proxy.get_parameter("obfs") {
+        let mut plugin_opts = Mapping::new();
+        insert(&mut plugin_opts, "mode", obfs);
+        if let Some(obfs_host) = proxy.get_parameter("obfs-host") {
+          insert(&mut plugin_opts, "host", obfs_host);
+        }
+        insert(&mut ret, "plugin", "obfs");
+        insert(&mut ret, "plugin-opts", plugin_opts);
+      }
+      insert_bool_parameter(&mut ret, proxy, "udp-relay", "udp");
+    }
+    "vmess" => {
+      insert(&mut ret, "uuid", proxy.get_parameter("username")?);
+      // An alterId of 0 makes Clash use AEAD, any other the legacy handshake
+      // Surge uses unless `vmess-aead` is set.
+      let alter_id = if bool_parameter(proxy, "vmess-aead") == Some(true) {
+        0u64
+      } else {
+        1
+      };
+      insert(&mut ret, "alterId", alter_id);
+      insert(
+        &mut ret,
+        "cipher",
+        proxy.get_parameter("encrypt-method").unwrap_or("auto"),
+      );
+      insert_bool_parameter(&mut ret, proxy, "tls", "tls");
+      insert_str_parameter(&mut ret, proxy, "sni", "servername");
+      insert_bool_parameter(&mut ret, proxy, "skip-cert-verify", "skip-cert-verify");
+      insert_ws_options(&mut ret, proxy);
+    }
+    "trojan" => {
+      insert(&mut ret, "password", proxy.get_parameter("password")?);
+      insert_str_parameter(&mut ret, proxy, "sni", "sni");
+      insert_bool_parameter(&mut ret, proxy, "skip-cert-verify", "skip-cert-verify");
+      insert_bool_parameter(&mut ret, proxy, "udp-relay", "udp");
+      insert_ws_options(&mut ret, proxy);
+    }
+    "http" | "https" | "socks5" | "socks5-tls" => {
+      insert_credentials(&mut ret, proxy);
+      if let "https" | "socks5-tls" = proxy.get_proto() {
+        insert(&mut ret, "tls", true);
+        insert_str_parameter(&mut ret, proxy, "sni", "sni");
+        insert_bool_parameter(&mut ret, proxy, "skip-cert-verify", "skip-cert-verify");
+      }
+    }
+    "snell" => {
+      insert(&mut ret, "psk", proxy.get_parameter("psk")?);
+      if let Some(version) = proxy.get_parameter("version") {
+        insert(&mut ret, "version", version.parse::<u64>().ok()?);
+      }
+      if let Some(obfs) = proxy.get_parameter("obfs") {
+        let mut obfs_opts = Mapping::new();
+        insert(&mut obfs_opts, "mode", obfs);
+        if let Some(obfs_host) = proxy.get_parameter("obfs-host") {
+          insert(&mut obfs_opts, "host", obfs_host);
+        }
+        insert(&mut ret, "obfs-opts", obfs_opts);
+      }
+    }
+    _ => return None,
+  }
+  Some(ret)
+}
+
+fn clash_proxy_group(group: &ProxyGroup, known_policies: &HashSet<String>) -> Mapping {
+  let mut ret = Mapping::new();
+  insert(&mut ret, "name", group.get_name());
+  // Clash cannot pick a policy by network, so a subnet group becomes a
+  // select of the policies it could pick.
+  let members: Vec<&str> = match group.get_type() {
+    ProxyGroupType::Subnet { default, mappings } => std::iter::once(default.as_str())
+      .chain(mappings.iter().map(|(_, policy)| policy.as_str()))
+      .collect(),
+    _ => group.get_proxies().iter().map(|name| name.as_str()).collect(),
+  };
+  let mut seen = HashSet::new();
+  let mut proxies: Vec<Value> = members
+    .into_iter()
+    .filter(|name| known_policies.contains(*name) && seen.insert(*name))
+    .map(Value::from)
+    .collect();
+  // Clash refuses to load a group without members.
+  if proxies.is_empty() {
+    proxies.push(Value::from("DIRECT"));
+  }
+  match group.get_type() {
+    ProxyGroupType::Select => {
+      insert(&mut ret, "type", "select");
+      insert(&mut ret, "proxies", proxies);
+    }
+    ProxyGroupType::UrlTest {
+      url,
+      interval,
+      tolerance,
+      ..
+    } => {
+      insert(&mut ret, "type", "url-test");
+      insert(&mut ret, "proxies", proxies);
+      insert(&mut ret, "url", url.as_str());
+      insert(&mut ret, "interval", u64::from(*interval));
+      insert(&mut ret, "tolerance", u64::from(*tolerance));
+    }
+    ProxyGroupType::Fallback { url, interval, .. } => {
+      insert(&mut ret, "type", "fallback");
+      insert(&mut ret, "proxies", proxies);
+      insert(&mut ret, "url", url.as_str());
+      insert(&mut ret, "interval", u64::from(*interval));
+    }
+    ProxyGroupType::LoadBalance {
+      url,
+      interval,
+      persistent,
+      ..
+    } => {
+      insert(&mut ret, "type", "load-balance");
+      insert(&mut ret, "proxies", proxies);
+      insert(&mut ret, "url", url.as_str());
+      insert(&mut ret, "interval", u64::from(*interval));
+      if *persistent {
+        insert(&mut ret, "strategy", "consistent-hashing");
+      }
+    }
+    ProxyGroupType::Subnet { .. } => {
+      insert(&mut ret, "type", "select");
+      insert(&mut ret, "proxies", proxies);
+    }
+    // Smart groups have no Clash equivalent, url-test is the closest.
+    ProxyGroupType::Smart => {
+      insert(&mut ret, "type", "url-test");
+      insert(&mut ret, "proxies", proxies);
+      insert(&mut ret, "url", DEFAULT_TEST_URL);
+      insert(&mut ret, "interval", u64::from(DEFAULT_TEST_INTERVAL));
+    }
+  }
+  ret
+}
+
+/// Translates a Surge rule into Clash syntax, or `None` if Clash has no
+/// equivalent for it. Like group members, policies Clash does not know drop
+/// the rule, except for the final one which falls back to DIRECT.
+fn clash_rule(rule: &str, known_policies: &HashSet<String>) -> Option<String> {
+  let parts: Vec<_> = rule.split(',').map(|part| part.trim()).collect();
+  match &parts[..] {
+    ["FINAL", policy, ..] if known_policies.contains(*policy) => Some(format!("MATCH,{}", policy)),
+    ["FINAL", ..] => Some(String::from("MATCH,DIRECT")),
+    [_, _, policy, ..] if !known_policies.contains(*policy) => None,
+    [rule_type, value, policy, options @ ..] if CLASH_RULE_TYPES.contains(rule_type) => {
+      let mut ret = vec![*rule_type, *value, *policy];
+      if options.contains(&"no-resolve") {
+        ret.push("no-resolve");
+      }
+      Some(ret.join(","))
+    }
+    _ => None,
+  }
+}
+
+fn get<'a>(mapping: &'a Mapping, key: &str) -> Option<&'a Value> {
+  mapping.get(&Value::from(key))
+}
+
+/// Clash values are loosely typed: ports, passwords and flags may be written
+/// as numbers, booleans or strings.
+fn get_string(mapping: &Mapping, key: &str) -> Option<String> {
+  match get(mapping, key)? {
+    Value::String(value) => Some(value.clone()),
+    Value::Number(value) => Some(value.to_string()),
+    Value::Bool(value) => Some(value.to_string()),
+    _ => None,
+  }
+}
+
+fn get_bool(mapping: &Mapping, key: &str) -> Option<bool> {
+  match get(mapping, key)? {
+    Value::Bool(value) => Some(*value),
+    Value::String(value) => Some(value == "true"),
+    _ => None,
+  }
+}
+
+fn set_string_parameter(proxy: &mut Proxy, mapping: &Mapping, clash_name: &str, surge_name: &str) {
+  if let Some(value) = get_string(mapping, clash_name) {
+    proxy.set_parameter(surge_name, &value);
+  }
+}
+
+fn set_bool_parameter(proxy: &mut Proxy, mapping: &Mapping, clash_name: &str, surge_name: &str) {
+  if let Some(value) = get_bool(mapping, clash_name) {
+    proxy.set_parameter(surge_name, &value.to_string());
+  }
+}
+
+fn set_ws_parameters(proxy: &mut Proxy, mapping: &Mapping) {
+  if get_string(mapping, "network").as_deref() != Some("ws") {
+    return;
+  }
+  proxy.set_parameter("ws", "true");
+  let ws_opts = get(mapping, "ws-opts").and_then(|opts| opts.as_mapping());
+  let path = ws_opts
+    .and_then(|opts| get_string(opts, "path"))
+    .or_else(|| get_string(mapping, "ws-path"));
+  if let Some(path) = path {
+    proxy.set_parameter("ws-path", &path);
+  }
+  let headers = ws_opts
+    .and_then(|opts| get(opts, "headers"))
+    .or_else(|| get(mapping, "ws-headers"))
+    .and_then(|headers| headers.as_mapping());
+  if let Some(headers) = headers {
+    let headers: Vec<_> = headers
+      .iter()
+      .filter_map(|(name, value)| Some(format!("{}:{}", name.as_str()?, value.as_str()?)))
+      .collect();
+    if !headers.is_empty() {
+      proxy.set_parameter("ws-headers", &headers.join("|"));
+    }
+  }
+}
+
+fn set_obfs_parameters(proxy: &mut Proxy, opts: Option<&Mapping>) {
+  if let Some(opts) = opts {
+    set_string_parameter(proxy, opts, "mode", "obfs");
+    set_string_parameter(proxy, opts, "host", "obfs-host");
+  }
+}
+
+/// A field the proxy cannot do without.
+fn require_string(mapping: &Mapping, key: &str) -> Result<String, String> {
+  get_string(mapping, key).ok_or_else(|| format!("missing `{}`", key))
+}
+
+fn surge_proxy(mapping: &Mapping) -> Result<Proxy, String> {
+  let name = require_string(mapping, "name")?;
+  let clash_type = require_string(mapping, "type")?;
+  let host = require_string(mapping, "server")?;
+  let port = require_string(mapping, "port")?;
+  let port = port
+    .parse::<u32>()
+    .map_err(|_| format!("invalid port `{}`", port))?;
+  let tls = get_bool(mapping, "tls").unwrap_or(false);
+  let proto = match (clash_type.as_str(), tls) {
+    ("http", true) => "https",
+    ("socks5", true) => "socks5-tls",
+    (proto, _) => proto,
+  };
+  let proto = ProxyProtocol::from_str(proto)
+    .ok_or_else(|| format!("unsupported proxy type `{}`", clash_type))?;
+  let mut proxy = Proxy::new(&name, proto, &host, port);
+  match clash_type.as_str() {
+    "ss" => {
+      proxy.set_parameter("encrypt-method", &require_string(mapping, "cipher")?);
+      proxy.set_parameter("password", &require_string(mapping, "password")?);
+      if let Some(plugin) = get_string(mapping, "plugin") {
+        // Surge only understands simple-obfs.
+        if plugin != "obfs" && plugin != "simple-obfs" {
+          return Err(format!("unsupported plugin `{}`", plugin));
+        }
+        set_obfs_parameters(
+          &mut proxy,
+          get(mapping, "plugin-opts").and_then(|opts| opts.as_mapping()),
+        );
+      }
+      set_bool_parameter(&mut proxy, mapping, "udp", "udp-relay");
+    }
+    "vmess" => {
+      proxy.set_parameter("username", &require_string(mapping, "uuid")?);
+      if let Some(cipher) = get_string(mapping, "cipher") {
+        if cipher != "auto" {
+          proxy.set_parameter("encrypt-method", &cipher);
+        }
+      }
+      let alter_id = get_string(mapping, "alterId").unwrap_or_else(|| String::from("0"));
+      if alter_id == "0" {
+        proxy.set_parameter("vmess-aead", "true");
+      }
+      set_bool_parameter(&mut proxy, mapping, "tls", "tls");
+      set_string_parameter(&mut proxy, mapping, "servername", "sni");
+      set_bool_parameter(&mut proxy, mapping, "skip-cert-verify", "skip-cert-verify");
+      set_ws_parameters(&mut proxy, mapping);
+    }
+    "trojan" => {
+      proxy.set_parameter("password", &require_string(mapping, "password")?);
+      set_string_parameter(&mut proxy, mapping, "sni", "sni");
+      set_bool_parameter(&mut proxy, mapping, "skip-cert-verify", "skip-cert-verify");
+      set_bool_parameter(&mut proxy, mapping, "udp", "udp-relay");
+      set_ws_parameters(&mut proxy, mapping);
+    }
+    "http" | "socks5" => {
+      if let (Some(username), Some(password)) = (
+        get_string(mapping, "username"),
+        get_string(mapping, "password"),
+      ) {
+        proxy.set_credentials(&username, &password);
+      }
+      if tls {
+        set_string_parameter(&mut proxy, mapping, "sni", "sni");
+        set_bool_parameter(&mut proxy, mapping, "skip-cert-verify", "skip-cert-verify");
+      }
+    }
+    "snell" => {
+      proxy.set_parameter("psk", &require_string(mapping, "psk")?);
+      set_string_parameter(&mut proxy, mapping, "version", "version");
+      set_obfs_parameters(
+        &mut proxy,
+        get(mapping, "obfs-opts").and_then(|opts| opts.as_mapping()),
+      );
+    }
+    _ => return Err(format!("unsupported proxy type `{}`", clash_type)),
+  }
+  Ok(proxy)
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ClashConfiguration {
+  #[serde(default)]
+  mode: String,
+  #[serde(default)]
+  proxies: Vec<Mapping>,
+  #[serde(rename = "proxy-groups", default)]
+  proxy_groups: Vec<Mapping>,
+  #[serde(default)]
+  rules: Vec<String>,
+}
+
+impl ClashConfiguration {
+  pub fn from_surge_configuration(surge_configuration: &SurgeConfiguration) -> ClashConfiguration {
+    let proxies: Vec<_> = surge_configuration
+      .get_proxies()
+      .iter()
+      .filter_map(clash_proxy)
+      .collect();
+
+    let mut known_policies: HashSet<String> = ["DIRECT", "REJECT"]
+      .iter()
+      .map(|name| String::from(*name))
+      .collect();
+    for proxy in &proxies {
+      if let Some(name) = proxy.get(&Value::from("name")).and_then(|name| name.as_str()) {
+        known_policies.insert(String::from(name));
+      }
+    }
+    for group in surge_configuration.get_proxy_groups() {
+      known_policies.insert(String::from(group.get_name()));
+    }
+
+    ClashConfiguration {
+      mode: String::from("rule"),
+      proxies,
+      proxy_groups: surge_configuration
+        .get_proxy_groups()
+        .iter()
+        .map(|group| clash_proxy_group(group, &known_policies))
+        .collect(),
+      rules: surge_configuration
+        .get_rules()
+        .iter()
+        .filter_map(|rule| clash_rule(rule, &known_policies))
+        .collect(),
+    }
+  }
+}
+
+impl ClashConfiguration {
+  /// Parses a Clash YAML configuration. Returns `None` unless the text is
+  /// valid YAML with at least one entry under `proxies`.
+  pub fn from_config_string(config: &str) -> Option<ClashConfiguration> {
+    serde_yaml::from_str::<ClashConfiguration>(config)
+      .ok()
+      .filter(|configuration| !configuration.proxies.is_empty())
+  }
+
+  /// Converts the proxies into their Surge equivalents. Proxies Surge does
+  /// not support, or missing a field, are reported rather than converted.
+  pub fn to_surge_configuration(&self) -> SurgeConfiguration {
+    let mut surge_configuration = SurgeConfiguration::default();
+    for mapping in &self.proxies {
+      match surge_proxy(mapping) {
+        Ok(proxy) => surge_configuration.push_proxy(proxy),
+        Err(reason) => surge_configuration
+          .report_rejected_proxy(&serde_json::to_string(mapping).unwrap_or_default(), reason),
+      }
+    }
+    surge_configuration
+  }
+}
+
+impl fmt::Display for ClashConfiguration {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match serde_yaml::to_string(self) {
+      Ok(yaml) => write!(f, "{}", yaml),
+      Err(_) => Err(fmt::Error),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::*;
+
+  fn surge_configuration() -> SurgeConfiguration {
+    let mut surge_configuration = SurgeConfiguration::default();
+    surge_configuration.add_proxy("HK 01 = ss, hk.example.com, 443, encrypt-method=aes-128-gcm, password=pwd, obfs=tls, obfs-host=bing.com, udp-relay=true");
+    surge_configuration.add_proxy("JP 01 = vmess, jp.example.com, 443, username=7c5c4a1e-0000-4000-8000-000000000000, ws=true, ws-path=/v2, ws-headers=Host:jp.example.com, tls=true, sni=jp.example.com");
+    surge_configuration.add_proxy("US 01 = trojan, us.example.com, 443, password=pwd, sni=us.example.com, skip-cert-verify=true");
+    surge_configuration.add_proxy("Office = https, office.example.com, 8443, user, pass");
+    surge_configuration.add_proxy("Snell = snell, snell.example.com, 8000, psk=abc, version=2, obfs=http");
+    surge_configuration.add_proxy("WG = wireguard, section-name=wg");
+    let mut auto = ProxyGroup::with_name("Auto");
+    auto.add_proxy("HK 01");
+    auto.add_proxy("JP 01");
+    auto.add_proxy("WG");
+    surge_configuration.add_proxy_group(auto);
+    let mut proxy = ProxyGroup::with_name("Proxy");
+    proxy.set_type(ProxyGroupType::Select);
+    proxy.add_proxy("Auto");
+    proxy.add_proxy("DIRECT");
+    surge_configuration.add_proxy_group(proxy);
+    surge_configuration.add_rule(String::from("DOMAIN-SUFFIX,google.com,Proxy"));
+    surge_configuration.add_rule(String::from("IP-CIDR,10.0.0.0/8,DIRECT,no-resolve"));
+    surge_configuration.add_rule(String::from("RULE-SET,https://example.com/list,Proxy"));
+    surge_configuration.add_rule(String::from("FINAL,Proxy,dns-failed"));
+    surge_configuration
+  }
+
+  #[test]
+  pub fn proxies_to_clash_should_work() {
+    let clash = ClashConfiguration::from_surge_configuration(&surge_configuration());
+    assert_eq!(clash.proxies.len(), 5);
+    let yaml = clash.to_string();
+    assert!(yaml.contains("cipher: aes-128-gcm"));
+    assert!(yaml.contains("plugin: obfs"));
+    assert!(yaml.contains("uuid: 7c5c4a1e-0000-4000-8000-000000000000"));
+    assert!(yaml.contains("network: ws"));
+    assert!(yaml.contains("Host: jp.example.com"));
+    assert!(yaml.contains("type: trojan"));
+    assert!(yaml.contains("psk: abc"));
+    assert!(!yaml.contains("WG"));
+  }
+
+  #[test]
+  pub fn vmess_alter_id_should_follow_aead() {
+    let alter_id = |definition: &str| {
+      let mut surge_configuration = SurgeConfiguration::default();
+      surge_configuration.add_proxy(definition);
+      let clash = ClashConfiguration::from_surge_configuration(&surge_configuration);
+      clash.proxies[0].get(&Value::from("alterId")).cloned()
+    };
+    assert_eq!(
+      alter_id("A = vmess, a.com, 443, username=7c5c4a1e-0000-4000-8000-000000000000"),
+      Some(Value::from(1))
+    );
+    assert_eq!(
+      alter_id("A = vmess, a.com, 443, username=7c5c4a1e-0000-4000-8000-000000000000, vmess-aead=true"),
+      Some(Value::from(0))
+    );
+  }
+
+  #[test]
+  pub fn smart_groups_should_become_url_test() {
+    let mut surge_configuration = SurgeConfiguration::default();
+    surge_configuration.add_proxy("HK 01 = https, hk.example.com, 443");
+    let mut pick = ProxyGroup::with_name("Pick");
+    pick.set_type(ProxyGroupType::Smart);
+    pick.add_proxy("HK 01");
+    surge_configuration.add_proxy_group(pick);
+    let clash = ClashConfiguration::from_surge_configuration(&surge_configuration);
+    let pick = &clash.proxy_groups[0];
+    assert_eq!(pick.get(&Value::from("type")), Some(&Value::from("url-test")));
+    assert_eq!(pick.get(&Value::from("proxies")), Some(&Value::from(vec!["HK 01"])));
+    assert_eq!(pick.get(&Value::from("url")), Some(&Value::from(DEFAULT_TEST_URL)));
+  }
+
+  #[test]
+  pub fn https_proxy_to_clash_should_work() {
+    let clash = ClashConfiguration::from_surge_configuration(&surge_configuration());
+    let office = &clash.proxies[3];
+    assert_eq!(office.get(&Value::from("type")), Some(&Value::from("http")));
+    assert_eq!(office.get(&Value::from("tls")), Some(&Value::from(true)));
+    assert_eq!(office.get(&Value::from("username")), Some(&Value::from("user")));
+    assert_eq!(office.get(&Value::from("password")), Some(&Value::from("pass")));
+  }
+
+  #[test]
+  pub fn groups_to_clash_should_work() {
+    let clash = ClashConfiguration::from_surge_configuration(&surge_configuration());
+    assert_eq!(clash.proxy_groups.len(), 2);
+    let auto = &clash.proxy_groups[0];
+    assert_eq!(auto.get(&Value::from("type")), Some(&Value::from("url-test")));
+    assert_eq!(
+      auto.get(&Value::from("proxies")),
+      Some(&Value::from(vec!["HK 01", "JP 01"]))
+    );
+    let proxy = &clash.proxy_groups[1];
+    assert_eq!(proxy.get(&Value::from("type")), Some(&Value::from("select")));
+    assert_eq!(
+      proxy.get(&Value::from("proxies")),
+      Some(&Value::from(vec!["Auto", "DIRECT"]))
+    );
+  }
+
+  #[test]
+  pub fn clash_proxies_to_surge_should_work() {
+    let clash = ClashConfiguration::from_config_string(
+      r#"
+port: 7890
+proxies:
+  - name: "HK 01"
+    type: ss
+    server: hk.example.com
+    port: 443
+    cipher: aes-128-gcm
+    password: 123456
+    plugin: obfs
+    plugin-opts:
+      mode: tls
+      host: bing.com
+  - { name: "JP 01", type: vmess, server: jp.example.com, port: "443", uuid: 7c5c4a1e-0000-4000-8000-000000000000, alterId: 0, cipher: auto, tls: true, network: ws, ws-opts: { path: /v2, headers: { Host: jp.example.com } } }
+  - name: Office
+    type: http
+    server: office.example.com
+    port: 8443
+    username: user
+    password: pass
+    tls: true
+  - name: VLESS
+    type: vless
+    server: vless.example.com
+    port: 443
+proxy-groups:
+  - name: Proxy
+    type: select
+    proxies: [HK 01]
+rules:
+  - MATCH,Proxy
+"#,
+    )
+    .expect("Parsing should work");
+    let surge_configuration = clash.to_surge_configuration();
+    let proxies: Vec<_> = surge_configuration
+      .get_proxies()
+      .iter()
+      .map(|proxy| proxy.to_string())
+      .collect();
+    assert_eq!(
+      proxies,
+      vec![
+        "HK 01 = ss,hk.example.com,443,encrypt-method=aes-128-gcm,obfs=tls,obfs-host=bing.com,password=123456",
+        "JP 01 = vmess,jp.example.com,443,tls=true,username=7c5c4a1e-0000-4000-8000-000000000000,vmess-aead=true,ws=true,ws-headers=Host:jp.example.com,ws-path=/v2",
+        "Office = https,office.example.com,8443,user,pass",
+      ]
+    );
+    let reasons: Vec<_> = surge_configuration
+      .get_diagnostics()
+      .iter()
+      .map(|diagnostic| diagnostic.get_reason())
+      .collect();
+    assert_eq!(reasons, vec!["unsupported proxy type `vless`"]);
+  }
+
+  #[test]
+  pub fn surge_profile_is_not_clash_configuration() {
+    assert!(ClashConfiguration::from_config_string(
+      "#!MANAGED-CONFIG http://a.com\n\n[General]\nhttp-listen = 0.0.0.0:1234\n\n[Proxy]\nA = ss, a.com, 443, encrypt-method=abc, password=ddd"
+    )
+    .is_none());
+  }
+
+  #[test]
+  pub fn rules_to_clash_should_work() {
+    let clash = ClashConfiguration::from_surge_configuration(&surge_configuration());
+    assert_eq!(
+      clash.rules,
+      vec![
+        "DOMAIN-SUFFIX,google.com,Proxy",
+        "IP-CIDR,10.0.0.0/8,DIRECT,no-resolve",
+        "MATCH,Proxy"
+      ]
+    );
+  }
+
+  #[test]
+  pub fn rules_to_unknown_policies_should_be_dropped() {
+    let mut surge_configuration = SurgeConfiguration::default();
+    surge_configuration.add_proxy("WG = wireguard, section-name=wg");
+    surge_configuration.add_rule(String::from("DOMAIN,home.example.com,WG"));
+    surge_configuration.add_rule(String::from("DOMAIN,ads.example.com,Dropped"));
+    surge_configuration.add_rule(String::from("IP-CIDR,10.0.0.0/8,DIRECT,no-resolve"));
+    surge_configuration.add_rule(String::from("FINAL,WG"));
+    let clash = ClashConfiguration::from_surge_configuration(&surge_configuration);
+    assert_eq!(
+      clash.rules,
+      vec!["IP-CIDR,10.0.0.0/8,DIRECT,no-resolve", "MATCH,DIRECT"]
+    );
+  }
+}
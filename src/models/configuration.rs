@@ -1,10 +1,68 @@
 use serde::{Deserialize, Serialize};
 
 use futures;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
+use super::mitm::Mitm;
+use super::protocol::ProxyProtocol;
+use super::rewrite;
+use super::script::ScriptConfiguration;
 use super::surge::{ProxyGroup, ProxyGroupType};
-use super::surge::SurgeConfiguration;
+use super::source::{self, SourceFormat};
+use super::surge::{self, Diagnostic, Proxy, SurgeConfiguration};
+use super::wireguard;
+
+/// The position after every given one.
+fn next_position(positions: impl Iterator<Item = Option<u32>>) -> u32 {
+  positions.flatten().map(|position| position + 1).max().unwrap_or(0)
+}
+
+/// `order` followed by the rest of `current`. Fails on ids not in `current`.
+fn reordered(current: Vec<String>, order: &[String]) -> Result<Vec<String>, String> {
+  if let Some(unknown) = order.iter().find(|id| !current.contains(id)) {
+    return Err(format!("unknown id `{}`", unknown));
+  }
+  let mut ret: Vec<String> = vec![];
+  for id in order.iter().chain(current.iter()) {
+    if !ret.contains(id) {
+      ret.push(id.clone());
+    }
+  }
+  Ok(ret)
+}
+
+/// How airports start the names of the proxies they use to show information
+/// rather than to carry traffic.
+const PSEUDO_NODE_PREFIXES: &[&str] = &[
+  "剩余流量",
+  "流量重置",
+  "距离下次重置",
+  "到期",
+  "过期时间",
+  "套餐到期",
+  "官网",
+  "官方网站",
+  "remaining traffic",
+  "traffic reset",
+  "expire",
+];
+
+/// `name`, or `name 2`, `name 3`, ... if it is taken.
+fn unique_name(name: &str, taken: &HashSet<String>) -> String {
+  let mut unique = String::from(name);
+  let mut suffix = 1;
+  while taken.contains(&unique) {
+    suffix += 1;
+    unique = format!("{} {}", name, suffix);
+  }
+  unique
+}
+
+/// Where this server is reachable from the devices, used for every URL that
+/// points back at us.
+fn server_host() -> String {
+  std::env::var("SERVER_HOST").unwrap_or(String::from("localhost:8080"))
+}
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub struct Configuration {
@@ -13,8 +71,37 @@ pub struct Configuration {
   airports: HashMap<String, AirportConfiguration>,
   rules: String,
   url_rewrites: String,
+  #[serde(default)]
+  hosts: String,
+  #[serde(default)]
+  mitm: Mitm,
+  #[serde(default)]
+  scripts: Vec<ScriptConfiguration>,
+  #[serde(default)]
+  header_rewrites: String,
+  #[serde(default)]
+  body_rewrites: String,
+  #[serde(default)]
+  map_locals: String,
   group_configurations: HashMap<String, GroupConfiguration>,
+  #[serde(default = "BuiltinGroupConfiguration::auto")]
+  auto_group: BuiltinGroupConfiguration,
+  #[serde(default = "BuiltinGroupConfiguration::proxy")]
+  proxy_group: BuiltinGroupConfiguration,
+  #[serde(default)]
+  group_rendering: GroupRendering,
+  #[serde(default)]
+  name_collision: NameCollision,
+  #[serde(default)]
+  proxy_deduplication: ProxyDeduplication,
+  #[serde(default)]
+  rename_rules: Vec<RenameRule>,
+  #[serde(default)]
+  pseudo_node_filter: PseudoNodeFilter,
   proxies: Vec<String>,
+  /// The `[WireGuard <name>]` sections our own WireGuard proxies refer to.
+  #[serde(default)]
+  wireguards: String,
 }
 
 impl Configuration {
@@ -22,10 +109,78 @@ impl Configuration {
     &self.name
   }
 
-  pub fn upsert_airport_configuration(&mut self, config: AirportConfiguration) {
+  /// An airport without a position keeps its old one, or goes last if it is
+  /// new.
+  pub fn upsert_airport_configuration(&mut self, mut config: AirportConfiguration) {
+    config.position = config.position.or_else(|| {
+      match self.airports.get(&config.airport_id) {
+        Some(existing) => existing.position,
+        None => Some(next_position(self.airports.values().map(|airport| airport.position))),
+      }
+    });
     self.airports.insert(config.airport_id.clone(), config);
   }
 
+  /// Airports in rendering order.
+  fn airport_list(&self) -> Vec<&AirportConfiguration> {
+    let mut airports: Vec<_> = self.airports.values().collect();
+    airports.sort_by_key(|airport| (airport.position.unwrap_or(u32::MAX), &airport.airport_id));
+    airports
+  }
+
+  /// Moves the listed airports to the front, in the order given.
+  pub fn reorder_airports(&mut self, airport_ids: &[String]) -> Result<(), String> {
+    let current = self
+      .airport_list()
+      .iter()
+      .map(|airport| airport.airport_id.clone())
+      .collect();
+    for (position, airport_id) in reordered(current, airport_ids)?.iter().enumerate() {
+      if let Some(airport) = self.airports.get_mut(airport_id) {
+        airport.position = Some(position as u32);
+      }
+    }
+    Ok(())
+  }
+
+  /// Moves the listed groups to the front, in the order given. Groups are
+  /// still rendered after the groups they include.
+  pub fn reorder_groups(&mut self, group_ids: &[String]) -> Result<(), String> {
+    let current = self.group_ids().into_iter().map(String::from).collect();
+    for (position, group_id) in reordered(current, group_ids)?.iter().enumerate() {
+      if let Some(group) = self.group_configurations.get_mut(group_id) {
+        group.position = Some(position as u32);
+      }
+    }
+    Ok(())
+  }
+
+  /// Makes the checks of every update at once, for a configuration that is
+  /// replaced as a whole.
+  pub fn validate(&self) -> Result<(), String> {
+    for group_id in self.group_ids() {
+      self.group_configurations[group_id].settings.validate()?;
+      self.group_configurations[group_id].matcher()?;
+    }
+    self.group_order()?;
+    self.auto_group.validate_auto()?;
+    self.proxy_group.validate()?;
+    for rename_rule in &self.rename_rules {
+      rename_rule.regex()?;
+    }
+    self.pseudo_node_filter.regexes()?;
+    rewrite::validate_lines(&self.header_rewrites, rewrite::validate_header_rewrite)?;
+    rewrite::validate_lines(&self.body_rewrites, rewrite::validate_body_rewrite)?;
+    rewrite::validate_lines(&self.map_locals, rewrite::validate_map_local)?;
+    rewrite::validate_lines(&self.hosts, surge::validate_host)?;
+    self.mitm.validate()?;
+    for script in &self.scripts {
+      script.validate()?;
+    }
+    self.own_surge_configuration()?;
+    Ok(())
+  }
+
   pub fn update_rules(&mut self, rules: &str) {
     self.rules = String::from(rules);
   }
@@ -38,10 +193,220 @@ impl Configuration {
     self.url_rewrites = String::from(url_rewrites);
   }
 
-  pub fn upsert_group_configuration(&mut self, config: GroupConfiguration) {
-    self
-      .group_configurations
-      .insert(config.group_id.clone(), config);
+  pub fn update_header_rewrites(&mut self, header_rewrites: &str) -> Result<(), String> {
+    rewrite::validate_lines(header_rewrites, rewrite::validate_header_rewrite)?;
+    self.header_rewrites = String::from(header_rewrites);
+    Ok(())
+  }
+
+  pub fn update_body_rewrites(&mut self, body_rewrites: &str) -> Result<(), String> {
+    rewrite::validate_lines(body_rewrites, rewrite::validate_body_rewrite)?;
+    self.body_rewrites = String::from(body_rewrites);
+    Ok(())
+  }
+
+  pub fn update_map_locals(&mut self, map_locals: &str) -> Result<(), String> {
+    rewrite::validate_lines(map_locals, rewrite::validate_map_local)?;
+    self.map_locals = String::from(map_locals);
+    Ok(())
+  }
+
+  pub fn update_hosts(&mut self, hosts: &str) -> Result<(), String> {
+    rewrite::validate_lines(hosts, surge::validate_host)?;
+    self.hosts = String::from(hosts);
+    Ok(())
+  }
+
+  /// Fails, keeping the old sections, on a malformed section or when one of
+  /// our WireGuard proxies would be left without its section.
+  pub fn update_wireguards(&mut self, wireguards: &str) -> Result<(), String> {
+    let previous = std::mem::replace(&mut self.wireguards, String::from(wireguards));
+    if let Err(reason) = self.own_surge_configuration() {
+      self.wireguards = previous;
+      return Err(reason);
+    }
+    Ok(())
+  }
+
+  pub fn update_mitm(&mut self, mitm: Mitm) -> Result<(), String> {
+    mitm.validate()?;
+    self.mitm = mitm;
+    Ok(())
+  }
+
+  /// Adds the script, or replaces the one with the same name.
+  pub fn upsert_script(&mut self, script: ScriptConfiguration) -> Result<(), String> {
+    script.validate()?;
+    match self
+      .scripts
+      .iter()
+      .position(|existing| existing.get_name() == script.get_name())
+    {
+      Some(index) => self.scripts[index] = script,
+      None => self.scripts.push(script),
+    }
+    Ok(())
+  }
+
+  pub fn get_script(&self, name: &str) -> Option<&ScriptConfiguration> {
+    self.scripts.iter().find(|script| script.get_name() == name)
+  }
+
+  pub fn has_airport(&self, airport_id: &str) -> bool {
+    self.airports.contains_key(airport_id)
+  }
+
+  /// The status of the airport given the body its subscription was last
+  /// fetched with, `None` if the download failed.
+  pub fn airport_status(&self, airport_id: &str, text: Option<&str>) -> Option<AirportStatus> {
+    let airport = self.airports.get(airport_id)?;
+    let source = text.and_then(|text| airport.format.parse(text));
+    Some(AirportStatus {
+      airport_id: airport.airport_id.clone(),
+      format: airport.format,
+      fetched: text.is_some(),
+      detected_format: text.map(SourceFormat::detect),
+      parsed_format: source.as_ref().map(|(format, _)| *format),
+      proxy_count: source
+        .as_ref()
+        .map(|(_, configuration)| configuration.get_proxies().len())
+        .unwrap_or(0),
+      diagnostic_count: source
+        .as_ref()
+        .map(|(_, configuration)| configuration.get_diagnostics().len())
+        .unwrap_or(0),
+    })
+  }
+
+  /// Lines of the airport's subscription that were dropped while parsing it.
+  /// Empty when the subscription could not be fetched at all.
+  pub fn airport_diagnostics(&self, airport_id: &str, text: Option<&str>) -> Option<Vec<Diagnostic>> {
+    let airport = self.airports.get(airport_id)?;
+    Some(
+      text
+        .and_then(|text| airport.surge_configuration(text))
+        .map(|configuration| configuration.get_diagnostics().clone())
+        .unwrap_or_default(),
+    )
+  }
+
+  pub fn get_group_rendering(&self) -> GroupRendering {
+    self.group_rendering
+  }
+
+  pub fn update_group_rendering(&mut self, group_rendering: GroupRendering) {
+    self.group_rendering = group_rendering;
+  }
+
+  pub fn update_name_collision(&mut self, name_collision: NameCollision) {
+    self.name_collision = name_collision;
+  }
+
+  pub fn update_proxy_deduplication(&mut self, proxy_deduplication: ProxyDeduplication) {
+    self.proxy_deduplication = proxy_deduplication;
+  }
+
+  pub fn update_rename_rules(&mut self, rename_rules: Vec<RenameRule>) -> Result<(), String> {
+    for rename_rule in &rename_rules {
+      rename_rule.regex()?;
+    }
+    self.rename_rules = rename_rules;
+    Ok(())
+  }
+
+  pub fn update_pseudo_node_filter(&mut self, pseudo_node_filter: PseudoNodeFilter) -> Result<(), String> {
+    pseudo_node_filter.regexes()?;
+    self.pseudo_node_filter = pseudo_node_filter;
+    Ok(())
+  }
+
+  pub fn update_auto_group(&mut self, auto_group: BuiltinGroupConfiguration) -> Result<(), String> {
+    auto_group.validate_auto()?;
+    self.auto_group = auto_group;
+    Ok(())
+  }
+
+  pub fn update_proxy_group(&mut self, proxy_group: BuiltinGroupConfiguration) -> Result<(), String> {
+    proxy_group.validate()?;
+    self.proxy_group = proxy_group;
+    Ok(())
+  }
+
+  /// Rejects the group if its patterns are invalid, or if it refers to an
+  /// unknown group or closes a cycle of groups. Positions are kept like for
+  /// airports.
+  pub fn upsert_group_configuration(&mut self, mut config: GroupConfiguration) -> Result<(), String> {
+    config.settings.validate()?;
+    config.matcher()?;
+    config.position = config.position.or_else(|| {
+      match self.group_configurations.get(&config.group_id) {
+        Some(existing) => existing.position,
+        None => Some(next_position(
+          self.group_configurations.values().map(|group| group.position),
+        )),
+      }
+    });
+    let group_id = config.group_id.clone();
+    let previous = self.group_configurations.insert(group_id.clone(), config);
+    if let Err(reason) = self.group_order() {
+      match previous {
+        Some(previous) => self.group_configurations.insert(group_id, previous),
+        None => self.group_configurations.remove(&group_id),
+      };
+      return Err(reason);
+    }
+    Ok(())
+  }
+
+  /// Group ids by position, then by id for groups stored without one.
+  fn group_ids(&self) -> Vec<&str> {
+    let mut groups: Vec<_> = self.group_configurations.values().collect();
+    groups.sort_by_key(|group| (group.position.unwrap_or(u32::MAX), &group.group_id));
+    groups.iter().map(|group| group.group_id.as_str()).collect()
+  }
+
+  /// Group ids ordered so that every group comes after the groups it
+  /// includes.
+  fn group_order(&self) -> Result<Vec<&str>, String> {
+    let mut order = vec![];
+    for group_id in self.group_ids() {
+      self.visit_group(group_id, &mut vec![], &mut order)?;
+    }
+    Ok(order)
+  }
+
+  fn visit_group<'a>(
+    &'a self,
+    group_id: &'a str,
+    path: &mut Vec<&'a str>,
+    order: &mut Vec<&'a str>,
+  ) -> Result<(), String> {
+    if order.contains(&group_id) {
+      return Ok(());
+    }
+    if let Some(start) = path.iter().position(|visiting| *visiting == group_id) {
+      let mut cycle = path[start..].to_vec();
+      cycle.push(group_id);
+      return Err(format!("groups form a cycle: {}", cycle.join(" -> ")));
+    }
+    path.push(group_id);
+    let group_config = &self.group_configurations[group_id];
+    let fallback = match &group_config.empty_policy {
+      EmptyGroupPolicy::Group(fallback) => Some(fallback),
+      _ => None,
+    };
+    for member in group_config.groups.iter().chain(fallback) {
+      if !self.group_configurations.contains_key(member) {
+        return Err(format!(
+          "group `{}` refers to unknown group `{}`",
+          group_id, member
+        ));
+      }
+      self.visit_group(member, path, order)?;
+    }
+    path.pop();
+    order.push(group_id);
+    Ok(())
   }
 }
 
@@ -50,11 +415,43 @@ pub struct AirportConfiguration {
   airport_id: String,
   airport_name: String,
   url: String,
+  #[serde(default)]
+  format: SourceFormat,
+  /// Whether the `[MITM]` hostnames of this airport end up in our profile.
+  #[serde(default)]
+  mitm: bool,
+  /// Whether the `[Host]` entries of this airport end up in our profile.
+  /// They can point any domain anywhere, so they are left out by default.
+  #[serde(default)]
+  hosts: bool,
+  /// Where the airport's proxies go relative to the other airports.
+  #[serde(default)]
+  position: Option<u32>,
+  /// Which airport keeps a proxy that several of them reach the same server
+  /// with, the highest wins.
+  #[serde(default)]
+  priority: i32,
 }
 
 impl AirportConfiguration {
-  async fn fetch_surge_configuration(&self) -> Option<SurgeConfiguration> {
-    SurgeConfiguration::from_url(&self.url).await
+  fn surge_configuration(&self, text: &str) -> Option<SurgeConfiguration> {
+    self
+      .format
+      .parse(text)
+      .map(|(_, configuration)| self.restrict(configuration))
+  }
+
+  /// Marks the proxies as ours and drops the sections the airport is not
+  /// trusted with.
+  fn restrict(&self, mut configuration: SurgeConfiguration) -> SurgeConfiguration {
+    configuration.set_airport(&self.airport_id);
+    if !self.mitm {
+      configuration.clear_mitm();
+    }
+    if !self.hosts {
+      configuration.clear_hosts();
+    }
+    configuration
   }
 
   #[cfg(test)]
@@ -63,8 +460,269 @@ impl AirportConfiguration {
       airport_id: String::from(id),
       airport_name: String::from(name),
       url: String::from(url),
+      format: SourceFormat::Auto,
+      mitm: false,
+      hosts: false,
+      position: None,
+      priority: 0,
+    }
+  }
+}
+
+/// The subscription bodies by airport id, `None` for those that could not be
+/// downloaded.
+pub type AirportTexts = HashMap<String, Option<String>>;
+
+/// What an airport's subscription looked like the last time it was fetched.
+#[derive(Serialize, Debug)]
+pub struct AirportStatus {
+  airport_id: String,
+  format: SourceFormat,
+  /// Whether the subscription could be downloaded at all.
+  fetched: bool,
+  /// What the body looks like, even if it then failed to parse.
+  detected_format: Option<SourceFormat>,
+  /// The format the body was parsed as, `None` if no parser accepted it.
+  parsed_format: Option<SourceFormat>,
+  proxy_count: usize,
+  diagnostic_count: usize,
+}
+
+/// The kind of group built from a `GroupConfiguration`.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum GroupType {
+  Select,
+  #[default]
+  UrlTest,
+  Fallback,
+  LoadBalance,
+  /// Picks a policy by network instead of from matched proxies.
+  Subnet,
+  Smart,
+}
+
+/// A network condition of a subnet group, such as `SSID:Home`, and the
+/// policy used on it.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct SubnetMapping {
+  condition: String,
+  policy: String,
+}
+
+/// How the members of our groups are rendered.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum GroupRendering {
+  /// Every matching proxy is listed in the group.
+  #[default]
+  Expand,
+  /// Surge 5 `include-all-proxies`, `policy-regex-filter` and
+  /// `include-other-group` options let the client do the matching. Groups
+  /// filtering by protocol or airport are still expanded, Surge cannot
+  /// express those.
+  Native,
+}
+
+/// What happens to proxies that have the same name, usually because two
+/// airports both call one of theirs `HK 01`.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum NameCollision {
+  /// Every `HK 01` but ours becomes `<airport name> HK 01`. Names no other
+  /// proxy has are kept. Group patterns are matched against the names once
+  /// prefixed.
+  Prefix,
+  /// The first `HK 01` keeps its name, the next ones become `HK 01 2`,
+  /// `HK 01 3`, ...
+  #[default]
+  Suffix,
+  /// Only the first `HK 01` is kept.
+  KeepFirst,
+}
+
+/// Whether proxies of different airports, or of one airport under two
+/// subscription URLs, that reach the same server are all rendered.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProxyDeduplication {
+  #[default]
+  Off,
+  /// The first proxy in airport order is kept.
+  KeepFirst,
+  /// The proxy of the airport with the highest priority is kept, the first
+  /// one among equals.
+  KeepPriority,
+}
+
+/// Rewrites the part of a proxy name matching `pattern`. `$1` and `${name}`
+/// in the replacement refer to the capture groups.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct RenameRule {
+  pattern: String,
+  replacement: String,
+}
+
+impl RenameRule {
+  #[cfg(test)]
+  pub fn new(pattern: &str, replacement: &str) -> RenameRule {
+    RenameRule {
+      pattern: String::from(pattern),
+      replacement: String::from(replacement),
     }
   }
+
+  fn regex(&self) -> Result<regex::Regex, String> {
+    regex::Regex::new(&self.pattern)
+      .map_err(|error| format!("invalid pattern `{}`: {}", self.pattern, error))
+  }
+}
+
+/// Which airport proxies are only there to show information, such as
+/// `剩余流量：12GB` pointing at `127.0.0.1`, and are left out. Off until
+/// enabled, so that no real proxy disappears from existing profiles.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[serde(default)]
+pub struct PseudoNodeFilter {
+  enabled: bool,
+  /// Whether names starting with one of `PSEUDO_NODE_PREFIXES` are left
+  /// out.
+  builtin_keywords: bool,
+  /// Left out if the name contains one, ignoring case.
+  keywords: Vec<String>,
+  /// Left out if the name matches one.
+  patterns: Vec<String>,
+  /// Whether proxies pointing at loopback or unspecified addresses are left
+  /// out.
+  unroutable_hosts: bool,
+  /// Whether the names of the proxies left out end up in the render
+  /// metadata.
+  report: bool,
+}
+
+impl Default for PseudoNodeFilter {
+  fn default() -> Self {
+    PseudoNodeFilter {
+      enabled: false,
+      builtin_keywords: true,
+      keywords: vec![],
+      patterns: vec![],
+      unroutable_hosts: true,
+      report: true,
+    }
+  }
+}
+
+impl PseudoNodeFilter {
+  fn regexes(&self) -> Result<Vec<regex::Regex>, String> {
+    self
+      .patterns
+      .iter()
+      .map(|pattern| {
+        regex::Regex::new(pattern).map_err(|error| format!("invalid pattern `{}`: {}", pattern, error))
+      })
+      .collect()
+  }
+
+  fn is_match(&self, proxy: &Proxy, regexes: &[regex::Regex]) -> bool {
+    let name = proxy.get_name().to_lowercase();
+    (self.builtin_keywords && PSEUDO_NODE_PREFIXES.iter().any(|prefix| name.starts_with(prefix)))
+      || self
+        .keywords
+        .iter()
+        .any(|keyword| name.contains(&keyword.to_lowercase()))
+      || regexes.iter().any(|regex| regex.is_match(proxy.get_name()))
+      || (self.unroutable_hosts && proxy.has_unroutable_host())
+  }
+}
+
+/// How a rendered group behaves, shared by the regex groups and the
+/// built-in ones.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+#[serde(default)]
+pub struct GroupSettings {
+  group_type: GroupType,
+  /// Test parameters of url-test, fallback and load-balance groups. Unset
+  /// ones keep our defaults; `tolerance` only applies to url-test.
+  url: Option<String>,
+  interval: Option<u32>,
+  tolerance: Option<u32>,
+  timeout: Option<u32>,
+  /// Whether a load-balance group keeps sending a site to the same proxy.
+  persistent: Option<bool>,
+  /// The policy of a subnet group on networks no mapping matches.
+  subnet_default: Option<String>,
+  subnet_mappings: Vec<SubnetMapping>,
+  hidden: bool,
+  no_alert: bool,
+}
+
+impl GroupSettings {
+  fn with_type(group_type: GroupType) -> GroupSettings {
+    GroupSettings {
+      group_type,
+      ..GroupSettings::default()
+    }
+  }
+
+  fn validate(&self) -> Result<(), String> {
+    if self.group_type != GroupType::Subnet {
+      return Ok(());
+    }
+    if self.subnet_default.as_deref().is_none_or(|policy| policy.trim().is_empty()) {
+      return Err(String::from("subnet group needs a default policy"));
+    }
+    match self
+      .subnet_mappings
+      .iter()
+      .find(|mapping| !mapping.condition.contains(':'))
+    {
+      Some(mapping) => Err(format!(
+        "subnet condition `{}` should look like `SSID:Home`",
+        mapping.condition
+      )),
+      None => Ok(()),
+    }
+  }
+
+  fn proxy_group_type(&self) -> ProxyGroupType {
+    let type_str = match self.group_type {
+      GroupType::Select => "select",
+      GroupType::UrlTest => "url-test",
+      GroupType::Fallback => "fallback",
+      GroupType::LoadBalance => "load-balance",
+      GroupType::Smart => "smart",
+      GroupType::Subnet => {
+        return ProxyGroupType::Subnet {
+          default: self.subnet_default.clone().unwrap_or_default(),
+          mappings: self
+            .subnet_mappings
+            .iter()
+            .map(|mapping| (mapping.condition.clone(), mapping.policy.clone()))
+            .collect(),
+        }
+      }
+    };
+    ProxyGroupType::with_defaults(type_str).unwrap_or_default()
+  }
+
+  /// An empty group with the configured type, parameters and flags.
+  fn proxy_group(&self, name: &str) -> ProxyGroup {
+    let mut group_type = self.proxy_group_type();
+    group_type.set_test_parameters(self.url.as_deref(), self.interval, self.tolerance, self.timeout);
+    if let Some(persistent) = self.persistent {
+      group_type.set_persistent(persistent);
+    }
+    let mut group = ProxyGroup::with_name(name);
+    group.set_type(group_type);
+    if self.hidden {
+      group.set_option("hidden", "true");
+    }
+    if self.no_alert {
+      group.set_option("no-alert", "true");
+    }
+    group
+  }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
@@ -72,6 +730,68 @@ pub struct GroupConfiguration {
   group_id: String,
   group_name: String,
   pattern: String,
+  /// Proxies matching this are left out even if they match `pattern`.
+  #[serde(default)]
+  exclude_pattern: Option<String>,
+  /// Only proxies with one of these protocols, e.g. `ss`. Empty for all.
+  #[serde(default)]
+  protocols: Vec<String>,
+  /// Only proxies from these airport ids. Empty for all, including our own
+  /// proxies.
+  #[serde(default)]
+  airports: Vec<String>,
+  #[serde(default)]
+  case_insensitive: bool,
+  /// Ids of other groups to include, rendered before the matched proxies.
+  #[serde(default)]
+  groups: Vec<String>,
+  #[serde(default)]
+  empty_policy: EmptyGroupPolicy,
+  /// Where the group is rendered relative to the other groups.
+  #[serde(default)]
+  position: Option<u32>,
+  #[serde(flatten)]
+  settings: GroupSettings,
+}
+
+/// What to render for a regex group that matches nothing, since Surge
+/// refuses to load a profile with an empty group.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum EmptyGroupPolicy {
+  /// Leave the group out, sending the traffic of the rules referring to it
+  /// to `DIRECT`.
+  Drop,
+  #[default]
+  Direct,
+  Reject,
+  /// Fall back to another group, by id.
+  Group(String),
+}
+
+/// Decides which proxies end up in a regex group.
+struct ProxyMatcher<'a> {
+  pattern: regex::Regex,
+  exclude_pattern: Option<regex::Regex>,
+  protocols: &'a [String],
+  airports: &'a [String],
+}
+
+impl ProxyMatcher<'_> {
+  fn is_match(&self, proxy: &Proxy) -> bool {
+    self.pattern.is_match(proxy.get_name())
+      && !self
+        .exclude_pattern
+        .as_ref()
+        .map(|exclude_pattern| exclude_pattern.is_match(proxy.get_name()))
+        .unwrap_or(false)
+      && (self.protocols.is_empty() || self.protocols.iter().any(|proto| proto == proxy.get_proto()))
+      && (self.airports.is_empty()
+        || proxy
+          .get_airport()
+          .map(|airport| self.airports.iter().any(|id| id == airport))
+          .unwrap_or(false))
+  }
 }
 
 impl GroupConfiguration {
@@ -81,8 +801,178 @@ impl GroupConfiguration {
       group_id: String::from(id),
       group_name: String::from(name),
       pattern: String::from(pattern),
+      exclude_pattern: None,
+      protocols: vec![],
+      airports: vec![],
+      case_insensitive: false,
+      groups: vec![],
+      empty_policy: EmptyGroupPolicy::default(),
+      position: None,
+      settings: GroupSettings::default(),
+    }
+  }
+
+  fn regex(&self, pattern: &str) -> Result<regex::Regex, String> {
+    regex::RegexBuilder::new(pattern)
+      .case_insensitive(self.case_insensitive)
+      .build()
+      .map_err(|error| format!("invalid pattern `{}`: {}", pattern, error))
+  }
+
+  /// The pattern as a Surge `policy-regex-filter`. Surge regexes support
+  /// lookahead, which takes care of the exclude pattern.
+  fn policy_regex_filter(&self) -> String {
+    let pattern = match &self.exclude_pattern {
+      Some(exclude_pattern) => format!("^(?!.*(?:{})).*(?:{})", exclude_pattern, self.pattern),
+      None => self.pattern.clone(),
+    };
+    if self.case_insensitive {
+      format!("(?i){}", pattern)
+    } else {
+      pattern
     }
   }
+
+  /// Surge applies `policy-regex-filter` to the policies of included groups
+  /// as well, so groups including others keep their matches expanded.
+  fn can_render_natively(&self) -> bool {
+    self.protocols.is_empty() && self.airports.is_empty() && self.groups.is_empty()
+  }
+
+  fn matcher(&self) -> Result<ProxyMatcher<'_>, String> {
+    if let Some(proto) = self
+      .protocols
+      .iter()
+      .find(|proto| ProxyProtocol::from_str(proto).is_none())
+    {
+      return Err(format!("unsupported protocol `{}`", proto));
+    }
+    Ok(ProxyMatcher {
+      pattern: self.regex(&self.pattern)?,
+      exclude_pattern: self
+        .exclude_pattern
+        .as_ref()
+        .map(|exclude_pattern| self.regex(exclude_pattern))
+        .transpose()?,
+      protocols: &self.protocols,
+      airports: &self.airports,
+    })
+  }
+}
+
+/// A member of a built-in group.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub enum GroupMember {
+  /// The built-in Auto group, under whatever name it is given.
+  Auto,
+  /// Every regex group.
+  Groups,
+  /// Every proxy.
+  Proxies,
+  /// A policy or group by name, e.g. `DIRECT`.
+  Policy(String),
+}
+
+fn enabled() -> bool {
+  true
+}
+
+/// The `Auto` and `Proxy` groups we add next to the regex groups. Members
+/// are rendered in the order they are listed.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct BuiltinGroupConfiguration {
+  #[serde(default = "enabled")]
+  enabled: bool,
+  name: String,
+  #[serde(flatten)]
+  settings: GroupSettings,
+  members: Vec<GroupMember>,
+}
+
+impl BuiltinGroupConfiguration {
+  /// A url-test of every proxy.
+  fn auto() -> BuiltinGroupConfiguration {
+    BuiltinGroupConfiguration {
+      enabled: true,
+      name: String::from("Auto"),
+      settings: GroupSettings::with_type(GroupType::UrlTest),
+      members: vec![GroupMember::Proxies],
+    }
+  }
+
+  /// A select of Auto, DIRECT, every regex group and every proxy.
+  fn proxy() -> BuiltinGroupConfiguration {
+    BuiltinGroupConfiguration {
+      enabled: true,
+      name: String::from("Proxy"),
+      settings: GroupSettings::with_type(GroupType::Select),
+      members: vec![
+        GroupMember::Auto,
+        GroupMember::Policy(String::from("DIRECT")),
+        GroupMember::Groups,
+        GroupMember::Proxies,
+      ],
+    }
+  }
+
+  fn validate_auto(&self) -> Result<(), String> {
+    self.validate()?;
+    if self.members.contains(&GroupMember::Auto) {
+      return Err(String::from("the Auto group cannot contain itself"));
+    }
+    Ok(())
+  }
+
+  fn validate(&self) -> Result<(), String> {
+    if self.name.trim().is_empty() {
+      return Err(String::from("group name must not be empty"));
+    }
+    if self.settings.group_type == GroupType::Subnet {
+      return Err(format!("group `{}` cannot be a subnet group", self.name));
+    }
+    if self
+      .members
+      .iter()
+      .any(|member| *member == GroupMember::Policy(self.name.clone()))
+    {
+      return Err(format!("group `{}` cannot contain itself", self.name));
+    }
+    Ok(())
+  }
+
+  /// `auto_name` is the name of the Auto group, or `None` when it is
+  /// disabled, in which case it is left out, like the `dropped` groups.
+  fn proxy_group(
+    &self,
+    auto_name: Option<&str>,
+    group_names: &[String],
+    proxy_names: &[String],
+    dropped: &[&str],
+    group_rendering: GroupRendering,
+  ) -> ProxyGroup {
+    let mut group = self.settings.proxy_group(&self.name);
+    for member in &self.members {
+      match member {
+        GroupMember::Auto => {
+          if let Some(auto_name) = auto_name.filter(|auto_name| *auto_name != self.name) {
+            group.add_proxy(auto_name);
+          }
+        }
+        GroupMember::Groups => group_names.iter().for_each(|name| group.add_proxy(name)),
+        GroupMember::Proxies => match group_rendering {
+          GroupRendering::Expand => proxy_names.iter().for_each(|name| group.add_proxy(name)),
+          GroupRendering::Native if !proxy_names.is_empty() => {
+            group.set_option("include-all-proxies", "true")
+          }
+          GroupRendering::Native => {}
+        },
+        GroupMember::Policy(name) if dropped.contains(&name.as_str()) => {}
+        GroupMember::Policy(name) => group.add_proxy(name),
+      }
+    }
+    group
+  }
 }
 
 impl Configuration {
@@ -92,54 +982,258 @@ impl Configuration {
       airports: HashMap::new(),
       generals: String::new(),
       url_rewrites: String::new(),
+      hosts: String::new(),
+      mitm: Mitm::default(),
+      scripts: vec![],
+      header_rewrites: String::new(),
+      body_rewrites: String::new(),
+      map_locals: String::new(),
       rules: String::new(),
       group_configurations: HashMap::new(),
+      auto_group: BuiltinGroupConfiguration::auto(),
+      proxy_group: BuiltinGroupConfiguration::proxy(),
+      group_rendering: GroupRendering::default(),
+      name_collision: NameCollision::default(),
+      proxy_deduplication: ProxyDeduplication::default(),
+      rename_rules: vec![],
+      pseudo_node_filter: PseudoNodeFilter::default(),
       proxies: vec![],
+      wireguards: String::new(),
     }
   }
 }
 
 impl Configuration {
-  pub async fn fetch_surge_configuration(&self) -> Option<SurgeConfiguration> {
-    let config_futures: Vec<_> = self
+  #[cfg(test)]
+  pub async fn fetch_surge_configuration(&self) -> Result<SurgeConfiguration, String> {
+    self.validate()?;
+    let texts = self.fetch_airport_texts().await;
+    self.render_airport_texts(&texts, self.group_rendering)
+  }
+
+  /// Downloads the subscription of every airport.
+  pub async fn fetch_airport_texts(&self) -> AirportTexts {
+    let text_futures: Vec<_> = self
       .airports
       .values()
-      .map(|airport_config| airport_config.fetch_surge_configuration())
+      .map(|airport_config| async move {
+        let text = source::fetch_text(&airport_config.url).await;
+        (airport_config.airport_id.clone(), text)
+      })
       .collect();
-    let surge_configurations: Vec<_> = futures::future::join_all(config_futures)
-      .await
-      .iter()
-      .filter(|option| option.is_some())
-      .map(|option| option.as_ref().unwrap().clone())
+    futures::future::join_all(text_futures).await.into_iter().collect()
+  }
+
+  /// Renders the profile out of the airports' subscriptions as fetched by
+  /// `fetch_airport_texts`. The configuration is expected to be valid.
+  pub fn render_airport_texts(
+    &self,
+    texts: &AirportTexts,
+    group_rendering: GroupRendering,
+  ) -> Result<SurgeConfiguration, String> {
+    let surge_configurations: Vec<_> = self
+      .airport_list()
+      .into_iter()
+      .filter_map(|airport_config| {
+        let text = texts.get(&airport_config.airport_id)?.as_deref()?;
+        airport_config.surge_configuration(text)
+      })
       .collect();
-    match Configuration::merge_surge_configurations(&surge_configurations[..]) {
+    self.render(&surge_configurations[..], group_rendering)
+  }
+
+  /// Builds the profile out of the airports' configurations, in airport
+  /// order. The configuration is expected to be valid.
+  fn render(
+    &self,
+    surge_configurations: &[SurgeConfiguration],
+    group_rendering: GroupRendering,
+  ) -> Result<SurgeConfiguration, String> {
+    match self.merge_surge_configurations(surge_configurations) {
       Some(mut surge_configuration) => {
+        let duplicates = self.deduplicate_proxies(&mut surge_configuration);
         self.add_proxies(&mut surge_configuration);
-        self.populate_surge_head(&mut surge_configuration);
+        let mut renamed = self.rename_proxies(&mut surge_configuration);
+        renamed.extend(self.resolve_name_collisions(&mut surge_configuration));
+        self.report_duplicate_proxies(&mut surge_configuration, &duplicates, &renamed);
+        self.populate_surge_head(&mut surge_configuration, group_rendering);
         self.populate_surge_generals(&mut surge_configuration);
         self.populate_surge_rules(&mut surge_configuration);
-        self.populate_surge_proxy_groups(&mut surge_configuration);
+        self.populate_surge_proxy_groups(&mut surge_configuration, group_rendering);
+        for (name, new_name) in &renamed {
+          surge_configuration.rename_policy(name, new_name);
+        }
         self.populate_surge_url_rewrites(&mut surge_configuration);
-        Some(surge_configuration)
+        self.populate_surge_rewrites(&mut surge_configuration);
+        self.populate_surge_hosts(&mut surge_configuration);
+        surge_configuration.merge_mitm(&self.mitm);
+        self.populate_surge_scripts(&mut surge_configuration);
+        Ok(surge_configuration)
       }
-      _ => None,
+      _ => Err(String::from("No airport configuration could be fetched")),
     }
   }
 
-  fn add_proxies(&self, surge_configuration: &mut SurgeConfiguration) {
+  /// Applies `rename_rules` in order to the names of all proxies, ours
+  /// included. A rule leaving nothing of a name is ignored for that proxy.
+  /// Returns the names that no proxy has any more, with their new name, so
+  /// that rules and groups referring to them can follow.
+  fn rename_proxies(
+    &self,
+    surge_configuration: &mut SurgeConfiguration,
+  ) -> Vec<(String, String)> {
+    // Patterns are checked by `validate` before rendering.
+    let rename_rules: Vec<_> = self
+      .rename_rules
+      .iter()
+      .filter_map(|rename_rule| Some((rename_rule.regex().ok()?, &rename_rule.replacement)))
+      .collect();
+    if rename_rules.is_empty() {
+      return vec![];
+    }
+    let mut renamed: Vec<(String, String)> = vec![];
+    for proxy in surge_configuration.get_proxies_mut() {
+      let original = String::from(proxy.get_name());
+      let mut name = original.clone();
+      for (regex, replacement) in &rename_rules {
+        let replaced = regex.replace_all(&name, replacement.as_str());
+        if !replaced.trim().is_empty() {
+          name = String::from(replaced.trim());
+        }
+      }
+      if name != original {
+        proxy.set_name(&name);
+        if !renamed.iter().any(|(old, _)| *old == original) {
+          renamed.push((original, name));
+        }
+      }
+    }
+    let names: HashSet<&str> = surge_configuration
+      .get_proxies()
+      .iter()
+      .map(|proxy| proxy.get_name())
+      .collect();
+    renamed.retain(|(old, _)| !names.contains(old.as_str()));
+    renamed
+  }
+
+  /// Our own proxies, with the WireGuard sections they refer to.
+  fn own_surge_configuration(&self) -> Result<SurgeConfiguration, String> {
+    let mut own = SurgeConfiguration::default();
+    own.set_wireguards(wireguard::parse_sections(&self.wireguards)?);
     for proxy_str in &self.proxies {
-      surge_configuration.add_proxy(proxy_str);
+      own.add_proxy(proxy_str);
     }
+    for proxy in own.get_proxies() {
+      let section_name = proxy
+        .get_parameter("section-name")
+        .filter(|_| proxy.get_proto() == ProxyProtocol::WireGuard.as_str());
+      if let Some(section_name) = section_name.filter(|name| !own.has_wireguard(name)) {
+        return Err(format!(
+          "proxy `{}` refers to unknown WireGuard section `{}`",
+          proxy.get_name(),
+          section_name
+        ));
+      }
+    }
+    Ok(own)
   }
 
-  fn populate_surge_head(&self, surge_configuration: &mut SurgeConfiguration) {
+  /// Adds our own proxies, renaming their WireGuard sections when an
+  /// airport already uses the name.
+  fn add_proxies(&self, surge_configuration: &mut SurgeConfiguration) {
+    // Checked by `validate` before rendering.
+    if let Ok(own) = self.own_surge_configuration() {
+      surge_configuration.merge(&own);
+    }
+  }
+
+  /// Gives proxies sharing a name distinct names according to
+  /// `name_collision`. Returns the names that no proxy has any more, with
+  /// the new name of the first proxy that had it, so that rules and groups
+  /// referring to them can follow.
+  fn resolve_name_collisions(
+    &self,
+    surge_configuration: &mut SurgeConfiguration,
+  ) -> Vec<(String, String)> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for proxy in surge_configuration.get_proxies() {
+      *counts.entry(String::from(proxy.get_name())).or_insert(0) += 1;
+    }
+    let mut taken: HashSet<String> = counts
+      .iter()
+      .filter(|(_, count)| **count == 1)
+      .map(|(name, _)| name.clone())
+      .collect();
+    let airport_names: HashMap<&str, &str> = self
+      .airports
+      .values()
+      .map(|airport| (airport.airport_id.as_str(), airport.airport_name.as_str()))
+      .collect();
+    let mut first_names: Vec<(String, String)> = vec![];
+    surge_configuration.get_proxies_mut().retain_mut(|proxy| {
+      let name = String::from(proxy.get_name());
+      if counts[&name] == 1 {
+        return true;
+      }
+      let first = !first_names.iter().any(|(original, _)| *original == name);
+      let candidate = match self.name_collision {
+        NameCollision::KeepFirst if !first => return false,
+        NameCollision::Prefix => match proxy.get_airport().and_then(|id| airport_names.get(id)) {
+          Some(airport_name) => format!("{} {}", airport_name, name),
+          None => name.clone(),
+        },
+        _ => name.clone(),
+      };
+      let new_name = unique_name(&candidate, &taken);
+      taken.insert(new_name.clone());
+      proxy.set_name(&new_name);
+      if first {
+        first_names.push((name, new_name));
+      }
+      true
+    });
+    for (name, _) in &first_names {
+      surge_configuration.report_name_collision(name);
+    }
+    first_names
+      .into_iter()
+      .filter(|(name, _)| !taken.contains(name))
+      .collect()
+  }
+
+  /// A requested group rendering is kept in the update URL, so that Surge
+  /// keeps getting the profile it asked for.
+  fn populate_surge_head(
+    &self,
+    surge_configuration: &mut SurgeConfiguration,
+    group_rendering: GroupRendering,
+  ) {
+    let query = match group_rendering {
+      _ if group_rendering == self.group_rendering => "",
+      GroupRendering::Expand => "?group_rendering=expand",
+      GroupRendering::Native => "?group_rendering=native",
+    };
     surge_configuration.set_head(String::from(format!(
-      "#!MANAGED-CONFIG {host}/api/v1/configurations/{config}/surge interval=43200 strict=false",
-      host = std::env::var("SERVER_HOST").unwrap_or(String::from("localhost:8080")),
-      config = self.name
+      "#!MANAGED-CONFIG {host}/api/v1/configurations/{config}/surge{query} interval=43200 strict=false",
+      host = server_host(),
+      config = self.name,
+      query = query
     )));
   }
 
+  fn populate_surge_scripts(&self, surge_configuration: &mut SurgeConfiguration) {
+    for script in &self.scripts {
+      let script_path = format!(
+        "{host}/api/v1/configurations/{config}/scripts/{script}.js",
+        host = server_host(),
+        config = self.name,
+        script = script.get_name()
+      );
+      surge_configuration.add_script(&script.entry(&script_path));
+    }
+  }
+
   fn populate_surge_generals(&self, surge_configuration: &mut SurgeConfiguration) {
     for general in self.generals.split("\n") {
       let clean_general = general.trim();
@@ -167,35 +1261,148 @@ impl Configuration {
     }
   }
 
-  fn populate_surge_proxy_groups(&self, surge_configuration: &mut SurgeConfiguration) {
-    let mut auto_group = ProxyGroup::with_name("Auto");
-    let mut all_proxy = ProxyGroup::with_name("Proxy");
-    all_proxy.set_type(ProxyGroupType::Select);
-    all_proxy.add_proxy("Auto");
-    all_proxy.add_proxy("DIRECT");
-    for proxy in surge_configuration.get_proxies() {
-      auto_group.add_proxy(proxy.get_name());
+  fn populate_surge_rewrites(&self, surge_configuration: &mut SurgeConfiguration) {
+    let non_empty_lines = |text: &'_ str| -> Vec<String> {
+      text
+        .split('\n')
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect()
+    };
+    for header_rewrite in non_empty_lines(&self.header_rewrites) {
+      surge_configuration.add_header_rewrite(header_rewrite);
+    }
+    for body_rewrite in non_empty_lines(&self.body_rewrites) {
+      surge_configuration.add_body_rewrite(body_rewrite);
+    }
+    for map_local in non_empty_lines(&self.map_locals) {
+      surge_configuration.add_map_local(map_local);
+    }
+  }
+
+  /// Runs after the airports are merged so our own entries override theirs.
+  fn populate_surge_hosts(&self, surge_configuration: &mut SurgeConfiguration) {
+    for host in self.hosts.split("\n") {
+      let clean_host = host.trim();
+      if !clean_host.is_empty() {
+        surge_configuration.add_host(String::from(clean_host));
+      }
     }
-    surge_configuration.add_proxy_group(auto_group);
+  }
 
-    for (group_name, group_config) in self.group_configurations.iter() {
-      let mut group = ProxyGroup::with_name(group_name);
-      let regex = regex::Regex::new(&group_config.pattern).unwrap();
-      for proxy in surge_configuration.get_proxies() {
-        if regex.is_match(proxy.get_name()) {
-          group.add_proxy(proxy.get_name());
+  fn populate_surge_proxy_groups(
+    &self,
+    surge_configuration: &mut SurgeConfiguration,
+    group_rendering: GroupRendering,
+  ) {
+    let proxy_names: Vec<String> = surge_configuration
+      .get_proxies()
+      .iter()
+      .map(|proxy| String::from(proxy.get_name()))
+      .collect();
+    // References are checked by `validate` before rendering.
+    let group_ids = self.group_order().unwrap_or_default();
+    let mut groups = vec![];
+    let mut dropped = vec![];
+    for group_id in group_ids {
+      let group_config = &self.group_configurations[group_id];
+      let mut group = group_config.settings.proxy_group(group_id);
+      // Subnet groups have no members, the pattern is not used.
+      if group_config.settings.group_type == GroupType::Subnet {
+        groups.push(group);
+        continue;
+      }
+      let nested: Vec<&str> = group_config
+        .groups
+        .iter()
+        .map(String::as_str)
+        .filter(|member| !dropped.contains(member))
+        .collect();
+      if group_rendering == GroupRendering::Native && !nested.is_empty() {
+        group.set_option("include-other-group", &nested.join(","));
+      } else {
+        nested.iter().for_each(|member| group.add_proxy(member));
+      }
+      // Patterns are checked by `validate` before rendering.
+      let matched: Vec<&str> = match group_config.matcher() {
+        Ok(matcher) => surge_configuration
+          .get_proxies()
+          .iter()
+          .filter(|proxy| matcher.is_match(proxy))
+          .map(|proxy| proxy.get_name())
+          .collect(),
+        Err(_) => vec![],
+      };
+      if group_rendering == GroupRendering::Native
+        && group_config.can_render_natively()
+        && !matched.is_empty()
+      {
+        group.set_option("include-all-proxies", "true");
+        group.set_option("policy-regex-filter", &group_config.policy_regex_filter());
+      } else {
+        matched.iter().for_each(|name| group.add_proxy(name));
+      }
+      if group.is_empty() {
+        surge_configuration.report_empty_group(group_id);
+        match &group_config.empty_policy {
+          EmptyGroupPolicy::Drop => {
+            dropped.push(group_id);
+            continue;
+          }
+          EmptyGroupPolicy::Direct => group.add_proxy("DIRECT"),
+          EmptyGroupPolicy::Reject => group.add_proxy("REJECT"),
+          // Rendered before this group, unless it was dropped as well.
+          EmptyGroupPolicy::Group(fallback) if !dropped.contains(&fallback.as_str()) => {
+            group.add_proxy(fallback)
+          }
+          EmptyGroupPolicy::Group(_) => group.add_proxy("DIRECT"),
         }
       }
+      groups.push(group);
+    }
+    for group_id in &dropped {
+      surge_configuration.redirect_rules(group_id, "DIRECT");
+    }
+    let group_names: Vec<String> = groups.iter().map(|group| String::from(group.get_name())).collect();
+
+    let auto_name = Some(self.auto_group.name.as_str()).filter(|_| self.auto_group.enabled);
+    if self.auto_group.enabled {
+      let auto_group = self.auto_group.proxy_group(
+        None,
+        &group_names,
+        &proxy_names,
+        &dropped,
+        group_rendering,
+      );
+      Configuration::add_builtin_group(surge_configuration, auto_group);
+    }
+    for group in groups {
       surge_configuration.add_proxy_group(group);
-      all_proxy.add_proxy(group_name);
     }
-    for proxy in surge_configuration.get_proxies() {
-      all_proxy.add_proxy(proxy.get_name());
+    if self.proxy_group.enabled {
+      let proxy_group = self.proxy_group.proxy_group(
+        auto_name,
+        &group_names,
+        &proxy_names,
+        &dropped,
+        group_rendering,
+      );
+      Configuration::add_builtin_group(surge_configuration, proxy_group);
     }
-    surge_configuration.add_proxy_group(all_proxy);
+  }
+
+  /// Built-in groups have no empty policy of their own, they get DIRECT.
+  fn add_builtin_group(surge_configuration: &mut SurgeConfiguration, mut group: ProxyGroup) {
+    if group.is_empty() {
+      surge_configuration.report_empty_group(group.get_name());
+      group.add_proxy("DIRECT");
+    }
+    surge_configuration.add_proxy_group(group);
   }
 
   fn merge_surge_configurations(
+    &self,
     surge_configurations: &[SurgeConfiguration],
   ) -> Option<SurgeConfiguration> {
     if surge_configurations.is_empty() {
@@ -206,8 +1413,116 @@ impl Configuration {
     for config in surge_configurations {
       ret.merge(config);
     }
+    self.filter_pseudo_nodes(&mut ret);
     Some(ret)
   }
+
+  /// Drops the proxies `pseudo_node_filter` matches, before they can be
+  /// mistaken for duplicates or renamed.
+  fn filter_pseudo_nodes(&self, surge_configuration: &mut SurgeConfiguration) {
+    let filter = &self.pseudo_node_filter;
+    if !filter.enabled {
+      return;
+    }
+    // Patterns are checked by `validate` before rendering.
+    let regexes = filter.regexes().unwrap_or_default();
+    let mut pseudo_nodes = vec![];
+    surge_configuration.get_proxies_mut().retain(|proxy| {
+      let is_pseudo_node = filter.is_match(proxy, &regexes);
+      if is_pseudo_node {
+        pseudo_nodes.push(String::from(proxy.get_name()));
+      }
+      !is_pseudo_node
+    });
+    if filter.report {
+      for name in &pseudo_nodes {
+        surge_configuration.report_pseudo_node(name);
+      }
+    }
+  }
+
+  /// Drops proxies reaching the same server as another one, keeping the one
+  /// `proxy_deduplication` prefers. Returns the dropped proxies along with
+  /// the one kept in their place.
+  fn deduplicate_proxies(
+    &self,
+    surge_configuration: &mut SurgeConfiguration,
+  ) -> Vec<(Proxy, Proxy)> {
+    if self.proxy_deduplication == ProxyDeduplication::Off {
+      return vec![];
+    }
+    let priority = |proxy: &Proxy| -> i32 {
+      proxy
+        .get_airport()
+        .and_then(|airport_id| self.airports.get(airport_id))
+        .map(|airport| airport.priority)
+        .unwrap_or(0)
+    };
+    let proxies = surge_configuration.get_proxies();
+    let keys: Vec<Option<String>> = proxies.iter().map(|proxy| proxy.server_key()).collect();
+    let mut kept: HashMap<&str, usize> = HashMap::new();
+    for (index, key) in keys.iter().enumerate() {
+      if let Some(key) = key {
+        let kept_index = kept.entry(key).or_insert(index);
+        if self.proxy_deduplication == ProxyDeduplication::KeepPriority
+          && priority(&proxies[index]) > priority(&proxies[*kept_index])
+        {
+          *kept_index = index;
+        }
+      }
+    }
+    let pairs: Vec<(usize, usize)> = keys
+      .iter()
+      .enumerate()
+      .filter_map(|(index, key)| Some((index, kept[key.as_deref()?])))
+      .filter(|(index, kept_index)| index != kept_index)
+      .collect();
+    let duplicates: Vec<(Proxy, Proxy)> = pairs
+      .iter()
+      .map(|(index, kept_index)| (proxies[*index].clone(), proxies[*kept_index].clone()))
+      .collect();
+    let dropped: HashSet<usize> = pairs.iter().map(|(index, _)| *index).collect();
+    let mut index = 0;
+    surge_configuration.get_proxies_mut().retain(|_| {
+      let keep = !dropped.contains(&index);
+      index += 1;
+      keep
+    });
+    duplicates
+  }
+
+  /// Reports the proxies `deduplicate_proxies` dropped under the final name
+  /// of the proxy kept in their place. It is looked up by server, as the
+  /// airports' proxies are unique by server once deduplicated, and otherwise
+  /// follows `renamed` like rules and groups do.
+  fn report_duplicate_proxies(
+    &self,
+    surge_configuration: &mut SurgeConfiguration,
+    duplicates: &[(Proxy, Proxy)],
+    renamed: &[(String, String)],
+  ) {
+    let kept_names: HashMap<String, String> = surge_configuration
+      .get_proxies()
+      .iter()
+      .filter(|proxy| proxy.get_airport().is_some())
+      .filter_map(|proxy| Some((proxy.server_key()?, String::from(proxy.get_name()))))
+      .collect();
+    for (proxy, kept_proxy) in duplicates {
+      let kept_name = match kept_proxy.server_key().and_then(|key| kept_names.get(&key)) {
+        Some(kept_name) => kept_name.clone(),
+        None => renamed
+          .iter()
+          .fold(String::from(kept_proxy.get_name()), |name, (old, new)| {
+            if name == *old {
+              new.clone()
+            } else {
+              name
+            }
+          }),
+      };
+      surge_configuration.report_duplicate_proxy(proxy, &kept_name);
+    }
+  }
 }
 
 #[cfg(test)]
@@ -219,7 +1534,722 @@ mod tests {
   async fn empty_config_to_surge_configuration_works() {
     let configuration = Configuration::empty("empty");
     let surge_configuration_opt = configuration.fetch_surge_configuration().await;
-    assert!(surge_configuration_opt.is_none());
+    assert!(surge_configuration_opt.is_err());
+  }
+
+  #[test]
+  fn airport_hosts_and_mitm_should_be_opt_in() {
+    let airport_configuration = || {
+      SurgeConfiguration::from_config_string(
+        "[Proxy]\nHK = https, hk.example.com, 443\n[Host]\nbank.com = 6.6.6.6\n[MITM]\nhostname = bank.com\n",
+      )
+      .unwrap()
+    };
+    let mut airport = AirportConfiguration::new("a", "Alpha", "");
+    let surge_configuration = airport.restrict(airport_configuration());
+    assert!(surge_configuration.get_hosts().is_empty());
+    assert!(surge_configuration.get_mitm().get_hostnames().is_empty());
+    assert_eq!(surge_configuration.get_proxies()[0].get_airport(), Some("a"));
+
+    airport.hosts = true;
+    airport.mitm = true;
+    let surge_configuration = airport.restrict(airport_configuration());
+    assert_eq!(surge_configuration.get_hosts(), &vec!["bank.com = 6.6.6.6"]);
+    assert_eq!(surge_configuration.get_mitm().get_hostnames(), &vec!["bank.com"]);
+  }
+
+  #[test]
+  fn scripts_should_point_back_at_server() {
+    let mut configuration = Configuration::empty("test");
+    configuration
+      .upsert_script(ScriptConfiguration::new(
+        "fix-headers",
+        "type=http-request,pattern=^https://api.example.com",
+        "$done({})",
+      ))
+      .unwrap();
+    assert!(configuration
+      .upsert_script(ScriptConfiguration::new("broken", "type=cron", ""))
+      .is_err());
+    assert_eq!(configuration.get_script("fix-headers").unwrap().get_body(), "$done({})");
+    assert!(configuration.get_script("broken").is_none());
+
+    let mut surge_configuration = SurgeConfiguration::default();
+    configuration.populate_surge_scripts(&mut surge_configuration);
+    assert!(surge_configuration.to_string().ends_with(&format!(
+      "[Script]\nfix-headers = type=http-request,pattern=^https://api.example.com,script-path={}/api/v1/configurations/test/scripts/fix-headers.js",
+      server_host()
+    )));
+  }
+
+  #[test]
+  fn groups_should_filter_proxies() {
+    let mut configuration = Configuration::empty("test");
+    let mut group = GroupConfiguration::new("group_1", "Hong Kong", "hong kong|hk");
+    group.exclude_pattern = Some(String::from("3x"));
+    group.protocols = vec![String::from("ss"), String::from("trojan")];
+    group.airports = vec![String::from("airport_1")];
+    group.case_insensitive = true;
+    configuration.upsert_group_configuration(group).unwrap();
+
+    let mut primary = SurgeConfiguration::default();
+    primary.add_proxy("HK 01 = trojan, hk1.example.com, 443, password=pwd");
+    primary.add_proxy("HK 02 3x = trojan, hk2.example.com, 443, password=pwd");
+    primary.add_proxy("Hong Kong 03 = https, hk3.example.com, 443");
+    primary.add_proxy("JP 01 = trojan, jp1.example.com, 443, password=pwd");
+    primary.set_airport("airport_1");
+    let mut secondary = SurgeConfiguration::default();
+    secondary.add_proxy("hk 04 = trojan, hk4.example.com, 443, password=pwd");
+    secondary.set_airport("airport_2");
+    let mut surge_configuration =
+      configuration.merge_surge_configurations(&[primary, secondary]).unwrap();
+    surge_configuration.add_proxy("hk 05 = trojan, hk5.example.com, 443, password=pwd");
+    configuration.populate_surge_proxy_groups(&mut surge_configuration, GroupRendering::Expand);
+    let group = surge_configuration
+      .get_proxy_groups()
+      .iter()
+      .find(|group| group.get_name() == "group_1")
+      .unwrap();
+    assert_eq!(group.get_proxies(), &vec!["HK 01"]);
+
+    let mut group = GroupConfiguration::new("group_2", "Broken", "(HK");
+    assert!(configuration.upsert_group_configuration(group).is_err());
+    group = GroupConfiguration::new("group_2", "Broken", "HK");
+    group.protocols = vec![String::from("shadowsocks")];
+    assert_eq!(
+      configuration.upsert_group_configuration(group),
+      Err(String::from("unsupported protocol `shadowsocks`"))
+    );
+  }
+
+  #[test]
+  fn replaced_configurations_should_be_validated() {
+    let mut configuration = Configuration::empty("test");
+    configuration
+      .upsert_group_configuration(GroupConfiguration::new("hk", "HK", "HK"))
+      .unwrap();
+    assert_eq!(configuration.validate(), Ok(()));
+
+    let mut group = GroupConfiguration::new("asia", "Asia", "JP");
+    group.groups = vec![String::from("asia")];
+    configuration
+      .group_configurations
+      .insert(String::from("asia"), group);
+    assert_eq!(
+      configuration.validate(),
+      Err(String::from("groups form a cycle: asia -> asia"))
+    );
+    configuration.group_configurations.remove("asia");
+
+    configuration
+      .group_configurations
+      .insert(String::from("jp"), GroupConfiguration::new("jp", "JP", "(JP"));
+    assert!(configuration.validate().is_err());
+    configuration.group_configurations.remove("jp");
+
+    configuration.rename_rules = vec![RenameRule::new("(HK", "")];
+    assert!(configuration.validate().is_err());
+    configuration.rename_rules = vec![];
+    configuration.pseudo_node_filter.patterns = vec![String::from("(HK")];
+    assert!(configuration.validate().is_err());
+  }
+
+  #[test]
+  fn nested_groups_should_be_ordered_and_checked() {
+    let mut configuration = Configuration::empty("test");
+    let mut group = GroupConfiguration::new("asia", "Asia", "JP");
+    group.groups = vec![String::from("hk")];
+    assert_eq!(
+      configuration.upsert_group_configuration(group),
+      Err(String::from("group `asia` refers to unknown group `hk`"))
+    );
+    configuration
+      .upsert_group_configuration(GroupConfiguration::new("hk", "HK", "HK"))
+      .unwrap();
+    let mut group = GroupConfiguration::new("asia", "Asia", "JP");
+    group.groups = vec![String::from("hk")];
+    configuration.upsert_group_configuration(group).unwrap();
+    let mut group = GroupConfiguration::new("hk", "HK", "HK");
+    group.groups = vec![String::from("asia")];
+    assert_eq!(
+      configuration.upsert_group_configuration(group),
+      Err(String::from("groups form a cycle: hk -> asia -> hk"))
+    );
+    assert!(configuration.group_configurations["hk"].groups.is_empty());
+
+    let mut surge_configuration = SurgeConfiguration::default();
+    surge_configuration.add_proxy("JP01 = https, jp.example.com, 443");
+    configuration.populate_surge_proxy_groups(&mut surge_configuration, GroupRendering::Expand);
+    let groups: Vec<_> = surge_configuration
+      .get_proxy_groups()
+      .iter()
+      .map(|group| group.get_name())
+      .collect();
+    assert_eq!(groups, vec!["Auto", "hk", "asia", "Proxy"]);
+    assert_eq!(
+      surge_configuration.get_proxy_groups()[2].get_proxies(),
+      &vec!["hk", "JP01"]
+    );
+  }
+
+  #[test]
+  fn native_rendering_should_leave_matching_to_surge() {
+    let mut configuration = Configuration::empty("test");
+    let mut group = GroupConfiguration::new("hk", "HK", "HK|Hong Kong");
+    group.exclude_pattern = Some(String::from("3x"));
+    group.case_insensitive = true;
+    configuration.upsert_group_configuration(group).unwrap();
+    let mut group = GroupConfiguration::new("trojan", "Trojan", "HK");
+    group.protocols = vec![String::from("trojan")];
+    group.settings.group_type = GroupType::Select;
+    configuration.upsert_group_configuration(group).unwrap();
+    let mut surge_configuration = SurgeConfiguration::default();
+    surge_configuration.add_proxy("HK01 = trojan, hk1.example.com, 443, password=pwd");
+    surge_configuration.add_proxy("HK02 = https, hk2.example.com, 443");
+    configuration.populate_surge_proxy_groups(&mut surge_configuration, GroupRendering::Native);
+    let groups: Vec<_> = surge_configuration
+      .get_proxy_groups()
+      .iter()
+      .map(|group| group.to_string())
+      .collect();
+    assert_eq!(
+      groups,
+      vec![
+        "Auto = url-test,url=http://www.qualcomm.cn/generate_204,interval=1800,tolerance=200,timeout=5,include-all-proxies=true",
+        "hk = url-test,url=http://www.qualcomm.cn/generate_204,interval=1800,tolerance=200,timeout=5,include-all-proxies=true,\
+policy-regex-filter=(?i)^(?!.*(?:3x)).*(?:HK|Hong Kong)",
+        "trojan = select,HK01",
+        "Proxy = select,Auto,DIRECT,hk,trojan,include-all-proxies=true",
+      ]
+    );
+
+    let mut group = GroupConfiguration::new("asia", "Asia", "HK02");
+    group.groups = vec![String::from("hk"), String::from("trojan")];
+    group.settings.group_type = GroupType::Select;
+    configuration.upsert_group_configuration(group).unwrap();
+    let mut surge_configuration = SurgeConfiguration::default();
+    surge_configuration.add_proxy("HK02 = https, hk2.example.com, 443");
+    configuration.populate_surge_proxy_groups(&mut surge_configuration, GroupRendering::Native);
+    let group = surge_configuration
+      .get_proxy_groups()
+      .iter()
+      .find(|group| group.get_name() == "asia")
+      .unwrap();
+    assert_eq!(
+      group.to_string(),
+      "asia = select,HK02,include-other-group=\"hk,trojan\""
+    );
+
+    configuration.group_rendering = GroupRendering::Native;
+    configuration.populate_surge_head(&mut surge_configuration, GroupRendering::Expand);
+    assert!(surge_configuration
+      .to_string()
+      .contains("/api/v1/configurations/test/surge?group_rendering=expand interval=43200"));
+  }
+
+  #[test]
+  fn empty_groups_should_follow_their_policy() {
+    let mut configuration = Configuration::empty("test");
+    let mut group = GroupConfiguration::new("us", "US", "US");
+    group.empty_policy = EmptyGroupPolicy::Drop;
+    configuration.upsert_group_configuration(group).unwrap();
+    let mut group = GroupConfiguration::new("jp", "JP", "JP");
+    group.empty_policy = EmptyGroupPolicy::Group(String::from("hk"));
+    assert!(configuration.upsert_group_configuration(group).is_err());
+    configuration
+      .upsert_group_configuration(GroupConfiguration::new("hk", "HK", "HK"))
+      .unwrap();
+    let mut group = GroupConfiguration::new("jp", "JP", "JP");
+    group.empty_policy = EmptyGroupPolicy::Group(String::from("hk"));
+    configuration.upsert_group_configuration(group).unwrap();
+    let mut group = GroupConfiguration::new("ads", "Ads", "Ads");
+    group.empty_policy = EmptyGroupPolicy::Reject;
+    group.groups = vec![String::from("us")];
+    group.settings.group_type = GroupType::Select;
+    configuration.upsert_group_configuration(group).unwrap();
+
+    let mut surge_configuration = SurgeConfiguration::default();
+    surge_configuration.add_proxy("HK01 = https, hk.example.com, 443");
+    surge_configuration.add_rule(String::from("DOMAIN-SUFFIX,netflix.com,us"));
+    surge_configuration.add_rule(String::from("DOMAIN-SUFFIX,ads.com,ads"));
+    surge_configuration.add_rule(String::from("FINAL,us,dns-failed"));
+    configuration.populate_surge_proxy_groups(&mut surge_configuration, GroupRendering::Native);
+    let groups: Vec<_> = surge_configuration
+      .get_proxy_groups()
+      .iter()
+      .map(|group| group.to_string())
+      .filter(|group| !group.contains("url-test"))
+      .collect();
+    assert_eq!(
+      groups,
+      vec![
+        "ads = select,REJECT",
+        "Proxy = select,Auto,DIRECT,hk,jp,ads,include-all-proxies=true",
+      ]
+    );
+    assert!(surge_configuration
+      .get_proxy_groups()
+      .iter()
+      .any(|group| group.to_string().starts_with("jp = url-test,hk,")));
+    assert_eq!(
+      surge_configuration.get_rules(),
+      &vec![
+        "DOMAIN-SUFFIX,netflix.com,DIRECT",
+        "DOMAIN-SUFFIX,ads.com,ads",
+        "FINAL,DIRECT,dns-failed",
+      ]
+    );
+    assert_eq!(
+      surge_configuration.get_metadata().get_empty_groups(),
+      &vec!["us", "jp", "ads"]
+    );
+
+    let mut surge_configuration = SurgeConfiguration::default();
+    configuration.populate_surge_proxy_groups(&mut surge_configuration, GroupRendering::Native);
+    assert!(surge_configuration
+      .get_proxy_groups()
+      .iter()
+      .all(|group| !group.is_empty()));
+    assert!(surge_configuration
+      .get_metadata()
+      .get_empty_groups()
+      .contains(&String::from("Auto")));
+  }
+
+  #[test]
+  fn airports_and_groups_should_keep_their_order() {
+    let mut configuration = Configuration::empty("test");
+    for airport_id in &["c", "a", "b"] {
+      configuration.upsert_airport_configuration(AirportConfiguration::new(airport_id, airport_id, ""));
+    }
+    let airport_ids = |configuration: &Configuration| -> Vec<String> {
+      configuration
+        .airport_list()
+        .iter()
+        .map(|airport| airport.airport_id.clone())
+        .collect()
+    };
+    assert_eq!(airport_ids(&configuration), vec!["c", "a", "b"]);
+    configuration.reorder_airports(&[String::from("b")]).unwrap();
+    configuration.upsert_airport_configuration(AirportConfiguration::new("c", "renamed", ""));
+    assert_eq!(airport_ids(&configuration), vec!["b", "c", "a"]);
+    assert_eq!(
+      configuration.reorder_airports(&[String::from("d")]),
+      Err(String::from("unknown id `d`"))
+    );
+
+    for group_id in &["z", "y", "x"] {
+      configuration
+        .upsert_group_configuration(GroupConfiguration::new(group_id, group_id, "."))
+        .unwrap();
+    }
+    let mut group = GroupConfiguration::new("y", "y", ".");
+    group.groups = vec![String::from("x")];
+    configuration.upsert_group_configuration(group).unwrap();
+    assert_eq!(configuration.group_order().unwrap(), vec!["z", "x", "y"]);
+    configuration
+      .reorder_groups(&[String::from("x"), String::from("z")])
+      .unwrap();
+    assert_eq!(configuration.group_order().unwrap(), vec!["x", "z", "y"]);
+  }
+
+  #[test]
+  fn name_collisions_should_be_resolved() {
+    let mut configuration = Configuration::empty("test");
+    configuration.upsert_airport_configuration(AirportConfiguration::new("a", "Alpha", ""));
+    configuration.upsert_airport_configuration(AirportConfiguration::new("b", "Beta", ""));
+    configuration.rules = String::from("DOMAIN,a.com,HK 01\nDOMAIN,b.com,JP 01");
+    configuration
+      .upsert_group_configuration(GroupConfiguration::new("hk", "HK", "HK"))
+      .unwrap();
+    let airport = |airport_id: &str| -> SurgeConfiguration {
+      let mut surge_configuration = SurgeConfiguration::default();
+      surge_configuration.add_proxy("HK 01 = https, hk.example.com, 443");
+      surge_configuration.add_proxy("JP 01 = https, jp.example.com, 443");
+      surge_configuration.set_airport(airport_id);
+      surge_configuration
+    };
+    let render = |configuration: &Configuration| -> SurgeConfiguration {
+      configuration
+        .render(&[airport("a"), airport("b")], GroupRendering::Expand)
+        .unwrap()
+    };
+    let proxy_names = |surge_configuration: &SurgeConfiguration| -> Vec<String> {
+      surge_configuration
+        .get_proxies()
+        .iter()
+        .map(|proxy| String::from(proxy.get_name()))
+        .collect()
+    };
+
+    let surge_configuration = render(&configuration);
+    assert_eq!(
+      proxy_names(&surge_configuration),
+      vec!["HK 01", "JP 01", "HK 01 2", "JP 01 2"]
+    );
+    assert_eq!(
+      surge_configuration.get_metadata().get_name_collisions(),
+      &vec!["HK 01", "JP 01"]
+    );
+
+    configuration.update_name_collision(NameCollision::KeepFirst);
+    assert_eq!(
+      proxy_names(&render(&configuration)),
+      vec!["HK 01", "JP 01"]
+    );
+
+    configuration.update_name_collision(NameCollision::Prefix);
+    configuration.proxies = vec![String::from("JP 01 = https, jp.example.org, 443")];
+    let surge_configuration = render(&configuration);
+    assert_eq!(
+      proxy_names(&surge_configuration),
+      vec!["Alpha HK 01", "Alpha JP 01", "Beta HK 01", "Beta JP 01", "JP 01"]
+    );
+    assert_eq!(
+      surge_configuration.get_rules(),
+      &vec!["DOMAIN,a.com,Alpha HK 01", "DOMAIN,b.com,JP 01"]
+    );
+    assert_eq!(
+      surge_configuration.get_proxy_groups()[1].get_proxies(),
+      &vec!["Alpha HK 01", "Beta HK 01"]
+    );
+  }
+
+  #[test]
+  fn duplicate_proxies_should_be_dropped() {
+    let mut configuration = Configuration::empty("test");
+    configuration.upsert_airport_configuration(AirportConfiguration::new("a", "Alpha", ""));
+    let mut airport = AirportConfiguration::new("b", "Beta", "");
+    airport.priority = 1;
+    configuration.upsert_airport_configuration(airport);
+    let mut alpha = SurgeConfiguration::default();
+    alpha.add_proxy("HK 01 = trojan, hk.example.com, 443, password=pwd");
+    alpha.add_proxy("HK 02 = trojan, hk.example.com, 443, password=other");
+    alpha.add_proxy("JP 01 = https, jp.example.com, 443, user, pwd");
+    alpha.set_airport("a");
+    let mut beta = SurgeConfiguration::default();
+    beta.add_proxy("Hong Kong = trojan, HK.example.com, 443, password=pwd");
+    beta.add_proxy("Japan = https, jp.example.com, 443, user, pwd");
+    beta.add_proxy("Japan 2 = https, jp.example.com, 8443, user, pwd");
+    beta.set_airport("b");
+    let proxy_names = |surge_configuration: &SurgeConfiguration| -> Vec<String> {
+      surge_configuration
+        .get_proxies()
+        .iter()
+        .map(|proxy| String::from(proxy.get_name()))
+        .collect()
+    };
+
+    let airports = [alpha, beta];
+    let render = |configuration: &Configuration| -> SurgeConfiguration {
+      configuration.render(&airports, GroupRendering::Expand).unwrap()
+    };
+    let surge_configuration = render(&configuration);
+    assert_eq!(surge_configuration.get_proxies().len(), 6);
+
+    configuration.update_proxy_deduplication(ProxyDeduplication::KeepFirst);
+    let surge_configuration = render(&configuration);
+    assert_eq!(
+      proxy_names(&surge_configuration),
+      vec!["HK 01", "HK 02", "JP 01", "Japan 2"]
+    );
+    assert_eq!(
+      surge_configuration.get_metadata().get_duplicate_proxies(),
+      vec![("Hong Kong", "HK 01"), ("Japan", "JP 01")]
+    );
+
+    configuration.update_proxy_deduplication(ProxyDeduplication::KeepPriority);
+    configuration
+      .update_rename_rules(vec![RenameRule::new("^Hong Kong$", "HK")])
+      .unwrap();
+    let surge_configuration = render(&configuration);
+    assert_eq!(
+      proxy_names(&surge_configuration),
+      vec!["HK 02", "HK", "Japan", "Japan 2"]
+    );
+    assert_eq!(
+      surge_configuration.get_metadata().get_duplicate_proxies(),
+      vec![("HK 01", "HK"), ("JP 01", "Japan")]
+    );
+  }
+
+  #[test]
+  fn rename_rules_should_apply_in_order() {
+    let mut configuration = Configuration::empty("test");
+    assert!(configuration
+      .update_rename_rules(vec![RenameRule::new("(HK", "")])
+      .is_err());
+    configuration
+      .update_rename_rules(vec![
+        RenameRule::new(r"^\W+", ""),
+        RenameRule::new(r"^(?P<region>\w+) Standard (\w+)", "${region}-$2"),
+        RenameRule::new(r"\s*\|.*Rate ([\d.]+)x$", " ×$1"),
+        RenameRule::new(r"^.*$", ""),
+      ])
+      .unwrap();
+    configuration
+      .upsert_group_configuration(GroupConfiguration::new("hk", "HK", "^HK-"))
+      .unwrap();
+    configuration.proxies = vec![String::from("🇭🇰 Ours = https, hk.example.org, 443")];
+
+    let mut airport = SurgeConfiguration::default();
+    airport.add_proxy("🇭🇰 HK Standard A01 | Media | Rate 0.5x = https, hk.example.com, 443");
+    airport.add_proxy("🇯🇵 JP Standard B02 = https, jp.example.com, 443");
+    let mut surge_configuration = configuration.merge_surge_configurations(&[airport]).unwrap();
+    configuration.add_proxies(&mut surge_configuration);
+    let renamed = configuration.rename_proxies(&mut surge_configuration);
+    configuration.populate_surge_proxy_groups(&mut surge_configuration, GroupRendering::Expand);
+    let proxy_names: Vec<_> = surge_configuration
+      .get_proxies()
+      .iter()
+      .map(|proxy| proxy.get_name())
+      .collect();
+    assert_eq!(proxy_names, vec!["HK-A01 ×0.5", "JP-B02", "Ours"]);
+    assert!(renamed.contains(&(String::from("🇭🇰 Ours"), String::from("Ours"))));
+    assert_eq!(
+      surge_configuration.get_proxy_groups()[1].get_proxies(),
+      &vec!["HK-A01 ×0.5"]
+    );
+  }
+
+  #[test]
+  fn airport_status_should_come_from_the_fetched_text() {
+    let mut configuration = Configuration::empty("test");
+    configuration.upsert_airport_configuration(AirportConfiguration::new("a", "A", "unused"));
+    let text = "[Proxy]\nHK 01 = https, hk.example.com, 443\nbroken line\n";
+    let status = configuration.airport_status("a", Some(text)).unwrap();
+    assert!(status.fetched);
+    assert_eq!(status.parsed_format, Some(SourceFormat::Surge));
+    assert_eq!(status.proxy_count, 1);
+    assert_eq!(status.diagnostic_count, 1);
+    assert_eq!(configuration.airport_diagnostics("a", Some(text)).unwrap().len(), 1);
+    let status = configuration.airport_status("a", None).unwrap();
+    assert!(!status.fetched);
+    assert!(configuration.airport_diagnostics("a", None).unwrap().is_empty());
+    assert!(configuration.airport_status("b", Some(text)).is_none());
+  }
+
+  #[test]
+  fn hosts_and_mitm_should_be_validated() {
+    let mut configuration = Configuration::empty("test");
+    assert_eq!(
+      configuration.update_hosts("a.com = 1.1.1.1\nb.com"),
+      Err(String::from("line 2: expected `domain = address`"))
+    );
+    assert!(configuration
+      .update_hosts("*.a.com = server:1.1.1.1\n\nb.com = 2.2.2.2")
+      .is_ok());
+    let mitm: Mitm = serde_json::from_str(r#"{"hostnames": ["a.com", "b.com c.com"]}"#).unwrap();
+    assert_eq!(
+      configuration.update_mitm(mitm),
+      Err(String::from("invalid MITM hostname `b.com c.com`"))
+    );
+    configuration.hosts = String::from("= 1.1.1.1");
+    assert!(configuration.validate().is_err());
+  }
+
+  #[test]
+  fn own_wireguard_proxies_should_have_their_sections() {
+    let mut configuration = Configuration::empty("test");
+    configuration.proxies = vec![String::from("Home = wireguard, section-name=Home")];
+    assert_eq!(
+      configuration.validate(),
+      Err(String::from("proxy `Home` refers to unknown WireGuard section `Home`"))
+    );
+    assert!(configuration.update_wireguards("private-key = abc=").is_err());
+    configuration
+      .update_wireguards("[WireGuard Home]\nprivate-key = own=\npeer = (public-key = def=, endpoint = home.example.org:51820)")
+      .unwrap();
+    assert!(configuration.validate().is_ok());
+    assert!(configuration.update_wireguards("").is_err());
+
+    let airport = SurgeConfiguration::from_config_string(
+      "[Proxy]\nWG = wireguard, section-name=Home\n\n[WireGuard Home]\nprivate-key = abc=\npeer = (public-key = def=, endpoint = a.example.com:51820)\n",
+    )
+    .unwrap();
+    let mut surge_configuration = configuration.merge_surge_configurations(&[airport]).unwrap();
+    configuration.add_proxies(&mut surge_configuration);
+    let section_names: Vec<_> = surge_configuration
+      .get_proxies()
+      .iter()
+      .filter_map(|proxy| proxy.get_parameter("section-name"))
+      .collect();
+    assert_eq!(section_names, vec!["Home", "Home 2"]);
+    assert!(surge_configuration
+      .to_string()
+      .ends_with("[WireGuard Home 2]\nprivate-key = own=\npeer = (public-key = def=, endpoint = home.example.org:51820)"));
+  }
+
+  #[test]
+  fn pseudo_nodes_should_be_filtered() {
+    let mut configuration = Configuration::empty("test");
+    let mut airport = SurgeConfiguration::default();
+    airport.add_proxy("剩余流量：12GB = https, info.example.com, 443");
+    airport.add_proxy("官网 abc.com = https, abc.com, 443");
+    airport.add_proxy("Traffic Reset = https, info.example.com, 443");
+    airport.add_proxy("Expiry 2027-01-01 = https, 127.0.0.1, 443");
+    airport.add_proxy("Notice = https, 0.0.0.0, 1");
+    airport.add_proxy("Join Us = https, localhost, 1");
+    airport.add_proxy("HK 01 = https, hk.example.com, 443");
+    airport.add_proxy("HK Channel = https, hk.example.com, 443");
+    airport.add_proxy("JP 02 Traffic x2 | Expire-free 官网推荐 = https, jp.example.com, 443");
+    let proxy_names = |surge_configuration: &SurgeConfiguration| -> Vec<String> {
+      surge_configuration
+        .get_proxies()
+        .iter()
+        .map(|proxy| String::from(proxy.get_name()))
+        .collect()
+    };
+
+    let surge_configuration = configuration
+      .merge_surge_configurations(&[airport.clone()])
+      .unwrap();
+    assert_eq!(surge_configuration.get_proxies().len(), 9);
+    assert!(surge_configuration.get_metadata().get_pseudo_nodes().is_empty());
+
+    let filter: PseudoNodeFilter = serde_json::from_str(r#"{"enabled": true}"#).unwrap();
+    configuration.update_pseudo_node_filter(filter).unwrap();
+    let surge_configuration = configuration
+      .merge_surge_configurations(&[airport.clone()])
+      .unwrap();
+    assert_eq!(
+      proxy_names(&surge_configuration),
+      vec!["HK 01", "HK Channel", "JP 02 Traffic x2 | Expire-free 官网推荐"]
+    );
+    assert_eq!(surge_configuration.get_metadata().get_pseudo_nodes().len(), 6);
+    assert_eq!(
+      surge_configuration.get_metadata().get_pseudo_nodes()[0],
+      "剩余流量：12GB"
+    );
+
+    let filter: PseudoNodeFilter = serde_json::from_str(r#"{"patterns": ["(Channel"]}"#).unwrap();
+    assert!(configuration.update_pseudo_node_filter(filter).is_err());
+    let filter: PseudoNodeFilter = serde_json::from_str(
+      r#"{"enabled": true, "builtin_keywords": false, "unroutable_hosts": false, "keywords": ["JOIN"], "patterns": ["Channel$"], "report": false}"#,
+    )
+    .unwrap();
+    configuration.update_pseudo_node_filter(filter).unwrap();
+    let surge_configuration = configuration.merge_surge_configurations(&[airport]).unwrap();
+    assert_eq!(
+      proxy_names(&surge_configuration),
+      vec![
+        "剩余流量：12GB",
+        "官网 abc.com",
+        "Traffic Reset",
+        "Expiry 2027-01-01",
+        "Notice",
+        "HK 01",
+        "JP 02 Traffic x2 | Expire-free 官网推荐"
+      ]
+    );
+    assert!(surge_configuration.get_metadata().get_pseudo_nodes().is_empty());
+  }
+
+  #[test]
+  fn builtin_groups_should_be_configurable() {
+    let mut configuration = Configuration::empty("test");
+    configuration
+      .upsert_group_configuration(GroupConfiguration::new("HK", "HK", "HK"))
+      .unwrap();
+    let proxy_group: BuiltinGroupConfiguration = serde_json::from_str(
+      r#"{"name": "Global", "group_type": "fallback", "members": ["groups", {"policy": "DIRECT"}, "auto"]}"#,
+    )
+    .unwrap();
+    configuration.update_proxy_group(proxy_group).unwrap();
+    let mut auto_group = BuiltinGroupConfiguration::auto();
+    auto_group.enabled = false;
+    configuration.update_auto_group(auto_group).unwrap();
+    let mut surge_configuration = SurgeConfiguration::default();
+    surge_configuration.add_proxy("HK01 = https, hk.example.com, 443");
+    configuration.populate_surge_proxy_groups(&mut surge_configuration, GroupRendering::Expand);
+    let groups: Vec<_> = surge_configuration
+      .get_proxy_groups()
+      .iter()
+      .map(|group| group.to_string())
+      .collect();
+    assert_eq!(
+      groups,
+      vec![
+        "HK = url-test,HK01,url=http://www.qualcomm.cn/generate_204,interval=1800,tolerance=200,timeout=5",
+        "Global = fallback,HK,DIRECT,url=http://www.qualcomm.cn/generate_204,interval=1800,timeout=5",
+      ]
+    );
+
+    let mut auto_group = BuiltinGroupConfiguration::auto();
+    auto_group.members.push(GroupMember::Auto);
+    assert!(configuration.update_auto_group(auto_group).is_err());
+    let mut proxy_group = BuiltinGroupConfiguration::proxy();
+    proxy_group.members.push(GroupMember::Policy(String::from("Proxy")));
+    assert!(configuration.update_proxy_group(proxy_group).is_err());
+  }
+
+  #[test]
+  fn stored_configurations_should_get_default_builtin_groups() {
+    let configuration: Configuration = serde_json::from_str(
+      r#"{"name": "old", "generals": "", "airports": {}, "rules": "", "url_rewrites": "", "group_configurations": {}, "proxies": []}"#,
+    )
+    .unwrap();
+    assert_eq!(configuration, Configuration::empty("old"));
+  }
+
+  #[test]
+  fn groups_should_use_their_type_and_parameters() {
+    let mut configuration = Configuration::empty("test");
+    let mut group = GroupConfiguration::new("group_1", "Backup", "HK");
+    group.settings.group_type = GroupType::Fallback;
+    configuration.upsert_group_configuration(group).unwrap();
+    let mut group = GroupConfiguration::new("group_2", "HK Auto", "HK");
+    group.settings.url = Some(String::from("http://test.example.com/204"));
+    group.settings.interval = Some(300);
+    group.settings.tolerance = Some(50);
+    group.settings.hidden = true;
+    configuration.upsert_group_configuration(group).unwrap();
+    let mut group = GroupConfiguration::new("group_3", "Streaming", "HK");
+    group.settings.group_type = GroupType::Select;
+    group.settings.tolerance = Some(50);
+    group.settings.no_alert = true;
+    configuration.upsert_group_configuration(group).unwrap();
+    let mut group = GroupConfiguration::new("group_4", "Balance", "HK");
+    group.settings.group_type = GroupType::LoadBalance;
+    group.settings.persistent = Some(true);
+    configuration.upsert_group_configuration(group).unwrap();
+    let mut group = GroupConfiguration::new("group_5", "Network", "HK");
+    group.settings.group_type = GroupType::Subnet;
+    assert_eq!(
+      configuration.upsert_group_configuration(group),
+      Err(String::from("subnet group needs a default policy"))
+    );
+    let mut group = GroupConfiguration::new("group_5", "Network", "HK");
+    group.settings.group_type = GroupType::Subnet;
+    group.settings.subnet_default = Some(String::from("Proxy"));
+    group.settings.subnet_mappings = vec![SubnetMapping {
+      condition: String::from("SSID:Home"),
+      policy: String::from("DIRECT"),
+    }];
+    configuration.upsert_group_configuration(group).unwrap();
+    let mut surge_configuration = SurgeConfiguration::default();
+    surge_configuration.add_proxy("HK01 = https, hk.example.com, 443");
+    configuration.populate_surge_proxy_groups(&mut surge_configuration, GroupRendering::Expand);
+    let mut groups: Vec<_> = surge_configuration
+      .get_proxy_groups()
+      .iter()
+      .map(|group| group.to_string())
+      .filter(|group| group.starts_with("group_"))
+      .collect();
+    groups.sort();
+    assert_eq!(
+      groups,
+      vec![
+        "group_1 = fallback,HK01,url=http://www.qualcomm.cn/generate_204,interval=1800,timeout=5",
+        "group_2 = url-test,HK01,url=http://test.example.com/204,interval=300,tolerance=50,timeout=5,hidden=true",
+        "group_3 = select,HK01,no-alert=true",
+        "group_4 = load-balance,HK01,url=http://www.qualcomm.cn/generate_204,interval=1800,timeout=5,persistent=true",
+        "group_5 = subnet,default=Proxy,SSID:Home=DIRECT",
+      ]
+    );
+    let mut proxy_group = BuiltinGroupConfiguration::proxy();
+    proxy_group.settings.group_type = GroupType::Subnet;
+    assert!(configuration.update_proxy_group(proxy_group).is_err());
   }
 
   #[tokio::test]
@@ -233,11 +2263,13 @@ mod tests {
       "airport_2",
       "airport_2_name",
       "https://gist.githubusercontent.com/nearsyh/45695b3332f02609c71a1a084dbfb5bf/raw/67c0c6b1ae2c5a8f044a5f7ea10d009c990c5469/surge_config_airport_2"));
-    configuration.upsert_group_configuration(GroupConfiguration::new(
-      "group_1",
-      "group_1_name",
-      "Media",
-    ));
+    configuration
+      .upsert_group_configuration(GroupConfiguration::new(
+        "group_1",
+        "group_1_name",
+        "Media",
+      ))
+      .unwrap();
     configuration.update_url_rewrites("^https?://(www.)?g.cn https://www.google.com 302");
     let surge_configuration = configuration.fetch_surge_configuration().await.unwrap();
     assert_eq!(surge_configuration.get_proxies().len(), 4);
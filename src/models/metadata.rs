@@ -0,0 +1,69 @@
+use serde::Serialize;
+
+/// What happened while rendering a profile that is not visible in it.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct RenderMetadata {
+  /// Groups that matched no proxies and fell back to their empty policy.
+  empty_groups: Vec<String>,
+  /// Proxy names used by more than one proxy before they were made unique.
+  name_collisions: Vec<String>,
+  /// Proxies dropped because they reach the same server as a kept one.
+  duplicate_proxies: Vec<DuplicateProxy>,
+  /// Names of the informational proxies airports use to tell the remaining
+  /// traffic, the expiry date and such.
+  pseudo_nodes: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateProxy {
+  name: String,
+  airport: Option<String>,
+  /// The final name of the kept proxy.
+  duplicate_of: String,
+}
+
+impl RenderMetadata {
+  pub fn add_empty_group(&mut self, group: &str) {
+    self.empty_groups.push(String::from(group));
+  }
+
+  pub fn add_name_collision(&mut self, name: &str) {
+    self.name_collisions.push(String::from(name));
+  }
+
+  pub fn add_duplicate_proxy(&mut self, name: &str, airport: Option<&str>, duplicate_of: &str) {
+    self.duplicate_proxies.push(DuplicateProxy {
+      name: String::from(name),
+      airport: airport.map(String::from),
+      duplicate_of: String::from(duplicate_of),
+    });
+  }
+
+  pub fn add_pseudo_node(&mut self, name: &str) {
+    self.pseudo_nodes.push(String::from(name));
+  }
+
+  #[cfg(test)]
+  pub fn get_empty_groups(&self) -> &Vec<String> {
+    &self.empty_groups
+  }
+
+  #[cfg(test)]
+  pub fn get_name_collisions(&self) -> &Vec<String> {
+    &self.name_collisions
+  }
+
+  #[cfg(test)]
+  pub fn get_duplicate_proxies(&self) -> Vec<(&str, &str)> {
+    self
+      .duplicate_proxies
+      .iter()
+      .map(|duplicate| (duplicate.name.as_str(), duplicate.duplicate_of.as_str()))
+      .collect()
+  }
+
+  #[cfg(test)]
+  pub fn get_pseudo_nodes(&self) -> &Vec<String> {
+    &self.pseudo_nodes
+  }
+}
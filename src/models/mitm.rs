@@ -0,0 +1,142 @@
+use serde::{Deserialize, Serialize};
+
+/// The keys of the `[MITM]` section we model, in the order they are rendered.
+const MITM_KEYS: &[&str] = &["hostname", "skip-server-cert-verify", "ca-passphrase", "ca-p12"];
+
+/// Settings of the Surge `[MITM]` section.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Mitm {
+  hostnames: Vec<String>,
+  skip_server_cert_verify: Option<bool>,
+  ca_passphrase: Option<String>,
+  ca_p12: Option<String>,
+}
+
+impl Mitm {
+  pub fn is_empty(&self) -> bool {
+    self.lines().iter().all(|line| line.is_empty())
+  }
+
+  #[cfg(test)]
+  pub fn get_hostnames(&self) -> &Vec<String> {
+    &self.hostnames
+  }
+
+  /// Hostnames are listed on one line, so they cannot be empty or hold
+  /// commas or spaces.
+  pub fn validate(&self) -> Result<(), String> {
+    let is_invalid = |hostname: &&String| {
+      hostname.is_empty() || hostname.contains(|c: char| c == ',' || c.is_whitespace())
+    };
+    match self.hostnames.iter().find(is_invalid) {
+      Some(hostname) => Err(format!("invalid MITM hostname `{}`", hostname)),
+      None => Ok(()),
+    }
+  }
+
+  /// Applies one `key = value` line of the section. Returns the position of
+  /// the key in `lines`, or `None` for keys we do not model.
+  pub fn set(&mut self, key: &str, value: &str) -> Result<Option<usize>, String> {
+    match key {
+      "hostname" => {
+        self.hostnames = value
+          .split(',')
+          .map(|hostname| String::from(hostname.trim()))
+          .filter(|hostname| !hostname.is_empty())
+          .collect();
+      }
+      "skip-server-cert-verify" => match value {
+        "true" | "1" => self.skip_server_cert_verify = Some(true),
+        "false" | "0" => self.skip_server_cert_verify = Some(false),
+        _ => return Err(format!("invalid boolean `{}`", value)),
+      },
+      "ca-passphrase" => self.ca_passphrase = Some(String::from(value)),
+      "ca-p12" => self.ca_p12 = Some(String::from(value)),
+      _ => return Ok(None),
+    }
+    Ok(MITM_KEYS.iter().position(|known| *known == key))
+  }
+
+  /// Adds the hostnames of `other` that are not already present.
+  pub fn union_hostnames(&mut self, other: &Mitm) {
+    for hostname in &other.hostnames {
+      if !self.hostnames.contains(hostname) {
+        self.hostnames.push(hostname.clone());
+      }
+    }
+  }
+
+  /// Unions the hostnames and takes every setting `other` has.
+  pub fn merge(&mut self, other: &Mitm) {
+    self.union_hostnames(other);
+    if other.skip_server_cert_verify.is_some() {
+      self.skip_server_cert_verify = other.skip_server_cert_verify;
+    }
+    if other.ca_passphrase.is_some() {
+      self.ca_passphrase = other.ca_passphrase.clone();
+    }
+    if other.ca_p12.is_some() {
+      self.ca_p12 = other.ca_p12.clone();
+    }
+  }
+
+  /// One line per entry of `MITM_KEYS`, empty when the key is not set.
+  pub fn lines(&self) -> Vec<String> {
+    let values = [
+      Some(self.hostnames.join(", ")).filter(|hostnames| !hostnames.is_empty()),
+      self.skip_server_cert_verify.map(|skip| skip.to_string()),
+      self.ca_passphrase.clone(),
+      self.ca_p12.clone(),
+    ];
+    MITM_KEYS
+      .iter()
+      .zip(values.iter())
+      .map(|(key, value)| match value {
+        Some(value) => format!("{} = {}", key, value),
+        None => String::new(),
+      })
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::*;
+
+  #[test]
+  pub fn mitm_merge_should_union_hostnames() {
+    let mut mitm = Mitm::default();
+    mitm.set("hostname", "a.com, *.b.com").unwrap();
+    let mut airport = Mitm::default();
+    airport.set("hostname", "*.b.com,c.com").unwrap();
+    airport.set("ca-passphrase", "airport").unwrap();
+    mitm.union_hostnames(&airport);
+    assert_eq!(mitm.get_hostnames(), &vec!["a.com", "*.b.com", "c.com"]);
+    assert_eq!(mitm.ca_passphrase, None);
+
+    let mut user = Mitm::default();
+    user.set("skip-server-cert-verify", "true").unwrap();
+    user.set("ca-passphrase", "secret").unwrap();
+    mitm.merge(&user);
+    assert_eq!(
+      mitm.lines(),
+      vec![
+        "hostname = a.com, *.b.com, c.com",
+        "skip-server-cert-verify = true",
+        "ca-passphrase = secret",
+        "",
+      ]
+    );
+  }
+
+  #[test]
+  pub fn mitm_set_should_validate() {
+    let mut mitm = Mitm::default();
+    assert_eq!(mitm.set("ca-p12", "MIIKPAIBAzCCCgYGCSqGSIb3DQEHAaCC=="), Ok(Some(3)));
+    assert_eq!(mitm.set("tcp-connection", "true"), Ok(None));
+    assert!(mitm.set("skip-server-cert-verify", "yes").is_err());
+    assert!(!mitm.is_empty());
+  }
+}
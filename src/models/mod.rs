@@ -1,6 +1,31 @@
+mod clash;
 mod configuration;
+mod metadata;
+mod mitm;
+mod protocol;
+mod quantumultx;
+mod rewrite;
+mod rule;
+mod script;
+mod share_link;
+mod sip008;
+mod source;
 mod surge;
+mod tokenizer;
+mod wireguard;
 
+pub use clash::ClashConfiguration;
 pub use configuration::Configuration;
 pub use configuration::AirportConfiguration;
-pub use configuration::GroupConfiguration;
\ No newline at end of file
+pub use configuration::AirportTexts;
+pub use configuration::BuiltinGroupConfiguration;
+pub use configuration::GroupConfiguration;
+pub use configuration::GroupRendering;
+pub use configuration::NameCollision;
+pub use configuration::ProxyDeduplication;
+pub use configuration::PseudoNodeFilter;
+pub use configuration::RenameRule;
+pub use metadata::RenderMetadata;
+pub use mitm::Mitm;
+pub use script::ScriptConfiguration;
+pub use surge::SurgeConfiguration;
\ No newline at end of file
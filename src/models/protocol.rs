@@ -0,0 +1,212 @@
+use std::collections::BTreeMap;
+
+/// Parameters every Surge proxy accepts regardless of protocol.
+const COMMON_PARAMETERS: &[&str] = &[
+  "interface",
+  "allow-other-interface",
+  "test-url",
+  "test-timeout",
+  "underlying-proxy",
+  "tfo",
+  "udp-relay",
+  "ip-version",
+  "no-error-alert",
+  "block-quic",
+  "ecn",
+  "shadow-tls-password",
+  "shadow-tls-sni",
+  "shadow-tls-version",
+];
+
+const TLS_PARAMETERS: &[&str] = &[
+  "sni",
+  "skip-cert-verify",
+  "server-cert-fingerprint-sha256",
+  "tls13",
+];
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ProxyProtocol {
+  Ss,
+  Vmess,
+  Trojan,
+  Snell,
+  Http,
+  Https,
+  Socks5,
+  Socks5Tls,
+  WireGuard,
+  Hysteria2,
+  Tuic,
+}
+
+impl ProxyProtocol {
+  pub fn from_str(proto: &str) -> Option<ProxyProtocol> {
+    match proto.trim() {
+      "ss" => Some(ProxyProtocol::Ss),
+      "vmess" => Some(ProxyProtocol::Vmess),
+      "trojan" => Some(ProxyProtocol::Trojan),
+      "snell" => Some(ProxyProtocol::Snell),
+      "http" => Some(ProxyProtocol::Http),
+      "https" => Some(ProxyProtocol::Https),
+      "socks5" => Some(ProxyProtocol::Socks5),
+      "socks5-tls" => Some(ProxyProtocol::Socks5Tls),
+      "wireguard" => Some(ProxyProtocol::WireGuard),
+      "hysteria2" => Some(ProxyProtocol::Hysteria2),
+      "tuic" => Some(ProxyProtocol::Tuic),
+      _ => None,
+    }
+  }
+
+  pub fn as_str(self) -> &'static str {
+    match self {
+      ProxyProtocol::Ss => "ss",
+      ProxyProtocol::Vmess => "vmess",
+      ProxyProtocol::Trojan => "trojan",
+      ProxyProtocol::Snell => "snell",
+      ProxyProtocol::Http => "http",
+      ProxyProtocol::Https => "https",
+      ProxyProtocol::Socks5 => "socks5",
+      ProxyProtocol::Socks5Tls => "socks5-tls",
+      ProxyProtocol::WireGuard => "wireguard",
+      ProxyProtocol::Hysteria2 => "hysteria2",
+      ProxyProtocol::Tuic => "tuic",
+    }
+  }
+
+  /// WireGuard proxies point at a `[WireGuard ...]` section instead of a
+  /// host and port.
+  pub fn has_endpoint(self) -> bool {
+    self != ProxyProtocol::WireGuard
+  }
+
+  /// Whether the definition may carry positional `username, password`.
+  pub fn has_credentials(self) -> bool {
+    matches!(
+      self,
+      ProxyProtocol::Http | ProxyProtocol::Https | ProxyProtocol::Socks5 | ProxyProtocol::Socks5Tls
+    )
+  }
+
+  fn required_parameters(self) -> &'static [&'static str] {
+    match self {
+      ProxyProtocol::Ss => &["encrypt-method", "password"],
+      ProxyProtocol::Vmess => &["username"],
+      ProxyProtocol::Trojan => &["password"],
+      ProxyProtocol::Snell => &["psk"],
+      ProxyProtocol::WireGuard => &["section-name"],
+      ProxyProtocol::Hysteria2 => &["password"],
+      ProxyProtocol::Tuic => &["token"],
+      _ => &[],
+    }
+  }
+
+  fn optional_parameters(self) -> &'static [&'static str] {
+    match self {
+      ProxyProtocol::Ss => &["obfs", "obfs-host", "obfs-uri"],
+      ProxyProtocol::Vmess => &[
+        "encrypt-method",
+        "vmess-aead",
+        "ws",
+        "ws-path",
+        "ws-headers",
+        "tls",
+      ],
+      ProxyProtocol::Trojan => &["ws", "ws-path", "ws-headers"],
+      ProxyProtocol::Snell => &["version", "obfs", "obfs-host", "reuse"],
+      ProxyProtocol::Http | ProxyProtocol::Https => &["always-use-connect"],
+      ProxyProtocol::Socks5 | ProxyProtocol::Socks5Tls => &[],
+      ProxyProtocol::WireGuard => &[],
+      ProxyProtocol::Hysteria2 => &["download-bandwidth", "port-hopping", "port-hopping-interval"],
+      ProxyProtocol::Tuic => &["alpn", "port-hopping", "port-hopping-interval"],
+    }
+  }
+
+  /// Returns whether Surge documents the parameter for this protocol. Unknown
+  /// parameters are still carried through untouched.
+  fn is_known_parameter(self, name: &str) -> bool {
+    self.required_parameters().contains(&name)
+      || self.optional_parameters().contains(&name)
+      || COMMON_PARAMETERS.contains(&name)
+      || TLS_PARAMETERS.contains(&name)
+  }
+
+  /// Returns the parameters Surge does not document for this protocol.
+  pub fn unknown_parameters(self, parameters: &BTreeMap<String, String>) -> Vec<&str> {
+    parameters
+      .keys()
+      .filter(|name| !self.is_known_parameter(name))
+      .map(String::as_str)
+      .collect()
+  }
+
+  /// Checks that every required parameter is present and non-empty. When one
+  /// is missing, unknown parameters are mentioned since they are usually typos.
+  pub fn validate(self, parameters: &BTreeMap<String, String>) -> Result<(), String> {
+    for name in self.required_parameters() {
+      match parameters.get(*name) {
+        Some(value) if !value.is_empty() => {}
+        _ => {
+          let unknown: Vec<_> = self
+            .unknown_parameters(parameters)
+            .iter()
+            .map(|name| format!("`{}`", name))
+            .collect();
+          let mut reason = format!(
+            "{} proxy is missing required parameter `{}`",
+            self.as_str(),
+            name
+          );
+          if !unknown.is_empty() {
+            reason.push_str(&format!(" (unknown parameters: {})", unknown.join(", ")));
+          }
+          return Err(reason);
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::*;
+
+  fn parameters(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+    entries
+      .iter()
+      .map(|(name, value)| (String::from(*name), String::from(*value)))
+      .collect()
+  }
+
+  #[test]
+  pub fn protocol_round_trip_should_work() {
+    for proto in &["ss", "vmess", "trojan", "snell", "http", "https", "socks5", "socks5-tls", "wireguard", "hysteria2", "tuic"] {
+      assert_eq!(ProxyProtocol::from_str(proto).unwrap().as_str(), *proto);
+    }
+    assert!(ProxyProtocol::from_str("direct").is_none());
+  }
+
+  #[test]
+  pub fn missing_required_parameter_should_fail() {
+    let result = ProxyProtocol::Ss.validate(&parameters(&[
+      ("encrypt-methd", "aes-128-gcm"),
+      ("password", "pwd"),
+    ]));
+    assert_eq!(
+      result,
+      Err(String::from(
+        "ss proxy is missing required parameter `encrypt-method` (unknown parameters: `encrypt-methd`)"
+      ))
+    );
+    assert!(ProxyProtocol::Vmess.validate(&parameters(&[("tls", "true")])).is_err());
+  }
+
+  #[test]
+  pub fn unknown_parameters_should_be_accepted() {
+    let parameters = parameters(&[("password", "pwd"), ("sni", "a.com"), ("future-option", "1")]);
+    assert_eq!(ProxyProtocol::Trojan.validate(&parameters), Ok(()));
+    assert_eq!(ProxyProtocol::Trojan.unknown_parameters(&parameters), vec!["future-option"]);
+  }
+}
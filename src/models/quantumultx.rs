@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+
+use super::protocol::ProxyProtocol;
+use super::surge::{Proxy, SurgeConfiguration};
+
+const SERVER_TYPES: &[&str] = &["shadowsocks", "vmess", "trojan", "http", "socks5"];
+
+fn key_value(part: &str) -> Option<(&str, &str)> {
+  let index = part.find('=')?;
+  Some((part[..index].trim(), part[index + 1..].trim()))
+}
+
+/// Whether the line looks like a Quantumult X server definition, e.g.
+/// `shadowsocks=example.com:443, method=aes-128-gcm, ...`.
+pub fn is_server_line(line: &str) -> bool {
+  key_value(line)
+    .map(|(server_type, _)| SERVER_TYPES.contains(&server_type))
+    .unwrap_or(false)
+}
+
+fn split_host_port(host_port: &str) -> Option<(&str, u32)> {
+  let index = host_port.rfind(':')?;
+  Some((&host_port[..index], host_port[index + 1..].parse::<u32>().ok()?))
+}
+
+fn set_tls_parameters(proxy: &mut Proxy, options: &HashMap<&str, &str>) {
+  if let Some(tls_host) = options.get("tls-host") {
+    proxy.set_parameter("sni", tls_host);
+  }
+  if options.get("tls-verification") == Some(&"false") {
+    proxy.set_parameter("skip-cert-verify", "true");
+  }
+}
+
+fn set_ws_parameters(proxy: &mut Proxy, options: &HashMap<&str, &str>) {
+  proxy.set_parameter("ws", "true");
+  if let Some(uri) = options.get("obfs-uri") {
+    proxy.set_parameter("ws-path", uri);
+  }
+  if let Some(host) = options.get("obfs-host") {
+    proxy.set_parameter("ws-headers", &format!("Host:{}", host));
+  }
+}
+
+fn proxy_from_line(line: &str) -> Option<Proxy> {
+  let mut parts = line.split(',');
+  let (server_type, host_port) = key_value(parts.next()?)?;
+  let (host, port) = split_host_port(host_port)?;
+  let options: HashMap<_, _> = parts.filter_map(key_value).collect();
+  let over_tls = options.get("over-tls") == Some(&"true");
+  let name = options
+    .get("tag")
+    .map(|tag| String::from(*tag))
+    .unwrap_or_else(|| format!("{}:{}", host, port));
+  let proto = match (server_type, over_tls) {
+    ("shadowsocks", _) => "ss",
+    ("http", true) => "https",
+    ("socks5", true) => "socks5-tls",
+    (server_type, _) => server_type,
+  };
+  let mut proxy = Proxy::new(&name, ProxyProtocol::from_str(proto)?, host, port);
+  match server_type {
+    "shadowsocks" => {
+      proxy.set_parameter("encrypt-method", options.get("method")?);
+      proxy.set_parameter("password", options.get("password")?);
+      match options.get("obfs") {
+        Some(obfs @ &"http") | Some(obfs @ &"tls") => {
+          proxy.set_parameter("obfs", obfs);
+          if let Some(obfs_host) = options.get("obfs-host") {
+            proxy.set_parameter("obfs-host", obfs_host);
+          }
+        }
+        // Websocket obfuscation needs v2ray-plugin, which Surge lacks.
+        Some(_) => return None,
+        None => {}
+      }
+      if let Some(udp_relay) = options.get("udp-relay") {
+        proxy.set_parameter("udp-relay", udp_relay);
+      }
+    }
+    "vmess" => {
+      proxy.set_parameter("username", options.get("password")?);
+      if options.get("aead") != Some(&"false") {
+        proxy.set_parameter("vmess-aead", "true");
+      }
+      match options.get("obfs") {
+        Some(&"ws") => set_ws_parameters(&mut proxy, &options),
+        Some(&"wss") => {
+          set_ws_parameters(&mut proxy, &options);
+          proxy.set_parameter("tls", "true");
+        }
+        Some(&"over-tls") => proxy.set_parameter("tls", "true"),
+        _ => {}
+      }
+      set_tls_parameters(&mut proxy, &options);
+    }
+    "trojan" => {
+      proxy.set_parameter("password", options.get("password")?);
+      if options.get("obfs") == Some(&"wss") {
+        set_ws_parameters(&mut proxy, &options);
+      }
+      set_tls_parameters(&mut proxy, &options);
+    }
+    "http" | "socks5" => {
+      if let (Some(username), Some(password)) = (options.get("username"), options.get("password")) {
+        proxy.set_credentials(username, password);
+      }
+      if over_tls {
+        set_tls_parameters(&mut proxy, &options);
+      }
+    }
+    _ => return None,
+  }
+  Some(proxy)
+}
+
+/// A Quantumult X server list, either standalone or as the `[server_local]`
+/// section of a full profile.
+#[derive(Debug)]
+pub struct QuantumultXConfiguration {
+  proxies: Vec<Proxy>,
+}
+
+impl QuantumultXConfiguration {
+  pub fn from_config_string(config: &str) -> Option<QuantumultXConfiguration> {
+    let proxies: Vec<_> = config
+      .lines()
+      .map(|line| line.trim())
+      .filter(|line| is_server_line(line))
+      .filter_map(proxy_from_line)
+      .collect();
+    if proxies.is_empty() {
+      None
+    } else {
+      Some(QuantumultXConfiguration { proxies })
+    }
+  }
+
+  pub fn to_surge_configuration(&self) -> SurgeConfiguration {
+    let mut surge_configuration = SurgeConfiguration::default();
+    for proxy in &self.proxies {
+      surge_configuration.push_proxy(proxy.clone());
+    }
+    surge_configuration
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::*;
+
+  #[test]
+  pub fn quantumultx_servers_should_work() {
+    let configuration = QuantumultXConfiguration::from_config_string(
+      r#"[server_local]
+shadowsocks=hk.example.com:443, method=aes-128-gcm, password=pwd, obfs=http, obfs-host=bing.com, udp-relay=true, tag=HK 01
+shadowsocks=ws.example.com:443, method=aes-128-gcm, password=pwd, obfs=wss, tag=Unsupported
+vmess=jp.example.com:443, method=chacha20-ietf-poly1305, password=7c5c4a1e-0000-4000-8000-000000000000, obfs=wss, obfs-host=jp.example.com, obfs-uri=/v2, tag=JP 01
+trojan=us.example.com:443, password=pwd, over-tls=true, tls-host=us.example.com, tls-verification=false, tag=US 01
+http=office.example.com:8443, username=user, password=pass, over-tls=true, tag=Office
+"#,
+    )
+    .expect("Parsing should work");
+    let proxies: Vec<_> = configuration
+      .to_surge_configuration()
+      .get_proxies()
+      .iter()
+      .map(|proxy| proxy.to_string())
+      .collect();
+    assert_eq!(
+      proxies,
+      vec![
+        "HK 01 = ss,hk.example.com,443,encrypt-method=aes-128-gcm,obfs=http,obfs-host=bing.com,password=pwd,udp-relay=true",
+        "JP 01 = vmess,jp.example.com,443,tls=true,username=7c5c4a1e-0000-4000-8000-000000000000,vmess-aead=true,ws=true,ws-headers=Host:jp.example.com,ws-path=/v2",
+        "US 01 = trojan,us.example.com,443,password=pwd,skip-cert-verify=true,sni=us.example.com",
+        "Office = https,office.example.com,8443,user,pass",
+      ]
+    );
+  }
+}
@@ -0,0 +1,178 @@
+const HEADER_ACTIONS: &[&str] = &[
+  "header-add",
+  "header-del",
+  "header-replace",
+  "header-replace-regex",
+];
+const MAP_LOCAL_KEYS: &[&str] = &["data", "data-type", "status-code", "header"];
+const MAP_LOCAL_DATA_TYPES: &[&str] = &["file", "text", "tiny-gif", "base64"];
+
+/// Splits on whitespace outside `"..."`. Quotes are kept, since these lines
+/// are validated and not rewritten.
+fn words(line: &str) -> Vec<&str> {
+  let mut words = vec![];
+  let mut start = None;
+  let mut in_quotes = false;
+  for (index, c) in line.char_indices() {
+    if c == '"' {
+      in_quotes = !in_quotes;
+    }
+    match (start, c.is_whitespace() && !in_quotes) {
+      (None, false) => start = Some(index),
+      (Some(word_start), true) => {
+        words.push(&line[word_start..index]);
+        start = None;
+      }
+      _ => {}
+    }
+  }
+  if let Some(word_start) = start {
+    words.push(&line[word_start..]);
+  }
+  words
+}
+
+/// `[http-request|http-response] <pattern> <action> <header> [<value>...]`.
+pub fn validate_header_rewrite(line: &str) -> Result<(), String> {
+  let words = words(line);
+  let words = match words.first() {
+    Some(&"http-request") | Some(&"http-response") => &words[1..],
+    _ => &words[..],
+  };
+  match words {
+    [_pattern, action, arguments @ ..] => {
+      let expected = match *action {
+        "header-del" => 1,
+        "header-add" | "header-replace" => 2,
+        "header-replace-regex" => 3,
+        _ => {
+          return Err(format!(
+            "unknown action `{}`, expected one of {}",
+            action,
+            HEADER_ACTIONS.join(", ")
+          ))
+        }
+      };
+      if arguments.len() < expected {
+        Err(format!("`{}` needs {} arguments", action, expected))
+      } else {
+        Ok(())
+      }
+    }
+    _ => Err(String::from("expected `<pattern> <action> <header>`")),
+  }
+}
+
+/// `http-request|http-response <pattern> <regex> <replacement> [...]`, or
+/// `http-request-jq|http-response-jq <pattern> <expression>`.
+pub fn validate_body_rewrite(line: &str) -> Result<(), String> {
+  match &words(line)[..] {
+    [rewrite_type, _pattern, arguments @ ..] => match *rewrite_type {
+      "http-request" | "http-response" => {
+        if arguments.is_empty() || arguments.len() % 2 != 0 {
+          Err(String::from("expected `<regex> <replacement>` pairs"))
+        } else {
+          Ok(())
+        }
+      }
+      "http-request-jq" | "http-response-jq" => {
+        if arguments.is_empty() {
+          Err(String::from("missing jq expression"))
+        } else {
+          Ok(())
+        }
+      }
+      _ => Err(format!("unknown type `{}`", rewrite_type)),
+    },
+    _ => Err(String::from("expected `<type> <pattern> <regex> <replacement>`")),
+  }
+}
+
+/// `<pattern> data="..." data-type=... status-code=... header="..."`.
+pub fn validate_map_local(line: &str) -> Result<(), String> {
+  let words = words(line);
+  if words.is_empty() {
+    return Err(String::from("missing pattern"));
+  }
+  for option in &words[1..] {
+    let (key, value) = option
+      .split_once('=')
+      .ok_or_else(|| format!("expected `key=value`, got `{}`", option))?;
+    let value = value.trim_matches('"');
+    match key {
+      "data-type" if !MAP_LOCAL_DATA_TYPES.contains(&value) => {
+        return Err(format!("unknown data-type `{}`", value))
+      }
+      "status-code" if value.parse::<u16>().is_err() => {
+        return Err(format!("invalid status-code `{}`", value))
+      }
+      _ if !MAP_LOCAL_KEYS.contains(&key) => return Err(format!("unknown option `{}`", key)),
+      _ => {}
+    }
+  }
+  Ok(())
+}
+
+/// Validates every non-empty line of a text block, naming the first bad one.
+pub fn validate_lines(text: &str, validate: fn(&str) -> Result<(), String>) -> Result<(), String> {
+  for (line_number, line) in text.split('\n').enumerate() {
+    let line = line.trim();
+    if !line.is_empty() {
+      validate(line).map_err(|reason| format!("line {}: {}", line_number + 1, reason))?;
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::*;
+
+  #[test]
+  pub fn words_should_respect_quotes() {
+    assert_eq!(
+      words(r#"^https://a.com  data="hello world" header="A:1|B:2""#),
+      vec!["^https://a.com", r#"data="hello world""#, r#"header="A:1|B:2""#]
+    );
+  }
+
+  #[test]
+  pub fn header_rewrite_should_validate() {
+    assert!(validate_header_rewrite("^https?://api.example.com header-add X-Token abc").is_ok());
+    assert!(validate_header_rewrite("http-response ^https://a.com header-del Set-Cookie").is_ok());
+    assert!(validate_header_rewrite("^https://a.com header-replace-regex User-Agent Mobile Desktop").is_ok());
+    assert_eq!(
+      validate_header_rewrite("^https://a.com header-add X-Token"),
+      Err(String::from("`header-add` needs 2 arguments"))
+    );
+    assert!(validate_header_rewrite("^https://a.com header-set X-Token abc").is_err());
+  }
+
+  #[test]
+  pub fn body_rewrite_should_validate() {
+    assert!(validate_body_rewrite(r#"http-response ^https://a.com/api "ads":\[.*?\] "ads":[]"#).is_ok());
+    assert!(validate_body_rewrite("http-response-jq ^https://a.com 'del(.ads)'").is_ok());
+    assert!(validate_body_rewrite("http-response ^https://a.com only-regex").is_err());
+    assert!(validate_body_rewrite("http-body ^https://a.com a b").is_err());
+  }
+
+  #[test]
+  pub fn map_local_should_validate() {
+    assert!(validate_map_local(r#"^https://a.com/ads data="{}" header="Content-Type:application/json""#).is_ok());
+    assert!(validate_map_local("^https://a.com/pixel data-type=tiny-gif status-code=200").is_ok());
+    assert_eq!(
+      validate_map_local("^https://a.com data-type=json"),
+      Err(String::from("unknown data-type `json`"))
+    );
+    assert!(validate_map_local("^https://a.com reject").is_err());
+  }
+
+  #[test]
+  pub fn validate_lines_should_report_line_number() {
+    assert_eq!(
+      validate_lines("^https://a.com data-type=text\n\n^https://b.com status-code=abc", validate_map_local),
+      Err(String::from("line 3: invalid status-code `abc`"))
+    );
+  }
+}
@@ -0,0 +1,74 @@
+/// Splits a rule on commas outside parentheses, so that the sub-rules of
+/// `AND,((DOMAIN,a.com),(DST-PORT,443)),Proxy` stay in one field.
+fn fields(rule: &str) -> Vec<&str> {
+  let mut fields = vec![];
+  let mut depth = 0usize;
+  let mut start = 0;
+  for (index, c) in rule.char_indices() {
+    match c {
+      '(' => depth += 1,
+      ')' => depth = depth.saturating_sub(1),
+      ',' if depth == 0 => {
+        fields.push(rule[start..index].trim());
+        start = index + 1;
+      }
+      _ => {}
+    }
+  }
+  fields.push(rule[start..].trim());
+  fields
+}
+
+/// `FINAL,policy` has no value, every other rule has the policy after it.
+fn policy_index(fields: &[&str]) -> Option<usize> {
+  let index = match fields.first() {
+    Some(&"FINAL") => 1,
+    _ => 2,
+  };
+  Some(index).filter(|index| *index < fields.len())
+}
+
+/// The policy a rule sends traffic to.
+pub fn policy(rule: &str) -> Option<&str> {
+  let fields = fields(rule);
+  policy_index(&fields).map(|index| fields[index])
+}
+
+/// The rule sending its traffic to `policy` instead. Rules without a policy
+/// are returned as they are.
+pub fn with_policy(rule: &str, policy: &str) -> String {
+  let mut fields = fields(rule);
+  match policy_index(&fields) {
+    Some(index) => {
+      fields[index] = policy;
+      fields.join(",")
+    }
+    None => String::from(rule),
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::*;
+
+  #[test]
+  pub fn rule_policy_should_work() {
+    assert_eq!(policy("DOMAIN-SUFFIX,google.com,Proxy"), Some("Proxy"));
+    assert_eq!(policy("IP-CIDR,10.0.0.0/8,DIRECT,no-resolve"), Some("DIRECT"));
+    assert_eq!(policy("FINAL,Proxy,dns-failed"), Some("Proxy"));
+    assert_eq!(policy("AND,((DOMAIN,a.com),(DST-PORT,443)),HK"), Some("HK"));
+    assert_eq!(policy("DOMAIN,a.com"), None);
+  }
+
+  #[test]
+  pub fn rule_with_policy_should_work() {
+    assert_eq!(with_policy("DOMAIN-SUFFIX,google.com,Proxy", "HK"), "DOMAIN-SUFFIX,google.com,HK");
+    assert_eq!(with_policy("FINAL,Proxy,dns-failed", "HK"), "FINAL,HK,dns-failed");
+    assert_eq!(
+      with_policy("AND,((DOMAIN,a.com),(DST-PORT,443)),HK", "JP"),
+      "AND,((DOMAIN,a.com),(DST-PORT,443)),JP"
+    );
+    assert_eq!(with_policy("DOMAIN,a.com", "HK"), "DOMAIN,a.com");
+  }
+}
@@ -0,0 +1,214 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use super::tokenizer::{self, Field};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ScriptType {
+  HttpRequest,
+  HttpResponse,
+  Cron,
+  Dns,
+  Generic,
+  Event,
+}
+
+impl ScriptType {
+  fn from_str(script_type: &str) -> Option<ScriptType> {
+    match script_type {
+      "http-request" => Some(ScriptType::HttpRequest),
+      "http-response" => Some(ScriptType::HttpResponse),
+      "cron" => Some(ScriptType::Cron),
+      "dns" => Some(ScriptType::Dns),
+      "generic" => Some(ScriptType::Generic),
+      "event" => Some(ScriptType::Event),
+      _ => None,
+    }
+  }
+
+  fn as_str(self) -> &'static str {
+    match self {
+      ScriptType::HttpRequest => "http-request",
+      ScriptType::HttpResponse => "http-response",
+      ScriptType::Cron => "cron",
+      ScriptType::Dns => "dns",
+      ScriptType::Generic => "generic",
+      ScriptType::Event => "event",
+    }
+  }
+
+  fn required_parameters(self) -> &'static [&'static str] {
+    match self {
+      ScriptType::HttpRequest | ScriptType::HttpResponse => &["pattern"],
+      ScriptType::Cron => &["cronexp"],
+      ScriptType::Event => &["event-name"],
+      ScriptType::Dns | ScriptType::Generic => &[],
+    }
+  }
+}
+
+/// One entry of the `[Script]` section, e.g.
+/// `name = type=http-response,pattern=^https://a.com,script-path=a.js`.
+#[derive(Debug, Clone)]
+pub struct Script {
+  name: String,
+  script_type: ScriptType,
+  script_path: String,
+  parameters: BTreeMap<String, String>,
+}
+
+impl Script {
+  fn from_name_definition(name: &str, definition: &str) -> Result<Script, String> {
+    let fields = tokenizer::tokenize(definition);
+    if let Some(Field::Positional(value)) = fields.first() {
+      return Err(format!("unexpected value `{}`", value));
+    }
+    let mut parameters = tokenizer::named(&fields);
+    let script_type = parameters
+      .remove("type")
+      .ok_or_else(|| String::from("missing `type`"))?;
+    let script_type = ScriptType::from_str(&script_type)
+      .ok_or_else(|| format!("unsupported script type `{}`", script_type))?;
+    let script_path = parameters
+      .remove("script-path")
+      .ok_or_else(|| String::from("missing `script-path`"))?;
+    for required in script_type.required_parameters() {
+      if !parameters.contains_key(*required) {
+        return Err(format!(
+          "{} script is missing required parameter `{}`",
+          script_type.as_str(),
+          required
+        ));
+      }
+    }
+    Ok(Script {
+      name: String::from(name.trim()),
+      script_type,
+      script_path,
+      parameters,
+    })
+  }
+
+  pub fn from_str(script: &str) -> Result<Script, String> {
+    match script.split_once('=') {
+      Some((name, definition)) => Script::from_name_definition(name, definition),
+      None => Err(String::from("expected `name = definition`")),
+    }
+  }
+}
+
+impl ToString for Script {
+  fn to_string(&self) -> String {
+    let mut definition_parts = vec![format!("type={}", self.script_type.as_str())];
+    for (name, value) in &self.parameters {
+      definition_parts.push([name, "=", &tokenizer::quote_whitespace(value)].concat());
+    }
+    definition_parts.push(format!("script-path={}", tokenizer::quote_whitespace(&self.script_path)));
+    format!("{} = {}", self.name, definition_parts.join(","))
+  }
+}
+
+/// A script we host ourselves. `definition` is the `[Script]` entry without
+/// the name and `script-path`, which points back at our server.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct ScriptConfiguration {
+  name: String,
+  definition: String,
+  body: String,
+}
+
+impl ScriptConfiguration {
+  pub fn get_name(&self) -> &str {
+    &self.name
+  }
+
+  pub fn get_body(&self) -> &str {
+    &self.body
+  }
+
+  /// The name ends up in the script URL, so it is kept to characters that
+  /// need no escaping.
+  pub fn validate(&self) -> Result<(), String> {
+    if self.name.is_empty()
+      || !self
+        .name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+      return Err(format!(
+        "script name `{}` may only contain letters, digits, `-` and `_`",
+        self.name
+      ));
+    }
+    Script::from_str(&self.entry("placeholder")).map(|_| ())
+  }
+
+  /// The `[Script]` entry for this script served from `script_path`.
+  pub fn entry(&self, script_path: &str) -> String {
+    format!(
+      "{} = {},script-path={}",
+      self.name,
+      self.definition,
+      tokenizer::quote(script_path)
+    )
+  }
+
+  #[cfg(test)]
+  pub fn new(name: &str, definition: &str, body: &str) -> ScriptConfiguration {
+    ScriptConfiguration {
+      name: String::from(name),
+      definition: String::from(definition),
+      body: String::from(body),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::*;
+
+  #[test]
+  pub fn script_from_str_should_work() {
+    let script = Script::from_str(
+      r#"Rewrite = type=http-response, pattern=^https://api\.example\.com/v1/, requires-body=1, max-size=0, script-path=https://a.com/a.js, argument="a=1,b=2""#,
+    )
+    .expect("Parsing should work");
+    assert_eq!(script.script_type, ScriptType::HttpResponse);
+    assert_eq!(
+      script.to_string(),
+      r#"Rewrite = type=http-response,argument="a=1,b=2",max-size=0,pattern=^https://api\.example\.com/v1/,requires-body=1,script-path=https://a.com/a.js"#
+    );
+    let script = Script::from_str(r#"Daily = type=cron, cronexp="0 8 * * *", script-path=daily.js"#)
+      .expect("Parsing should work");
+    assert_eq!(
+      script.to_string(),
+      r#"Daily = type=cron,cronexp="0 8 * * *",script-path=daily.js"#
+    );
+  }
+
+  #[test]
+  pub fn invalid_scripts_should_fail() {
+    assert_eq!(
+      Script::from_str("A = type=cron, script-path=a.js").unwrap_err(),
+      "cron script is missing required parameter `cronexp`"
+    );
+    assert_eq!(
+      Script::from_str("A = type=rule, script-path=a.js").unwrap_err(),
+      "unsupported script type `rule`"
+    );
+    assert_eq!(
+      Script::from_str("A = type=generic").unwrap_err(),
+      "missing `script-path`"
+    );
+  }
+
+  #[test]
+  pub fn script_configuration_should_validate() {
+    assert!(ScriptConfiguration::new("fix-headers", "type=http-request,pattern=^https://a.com", "$done({})")
+      .validate()
+      .is_ok());
+    assert!(ScriptConfiguration::new("../x", "type=generic", "").validate().is_err());
+    assert!(ScriptConfiguration::new("x", "type=http-request", "").validate().is_err());
+  }
+}
@@ -0,0 +1,102 @@
+use serde::Deserialize;
+
+use super::protocol::ProxyProtocol;
+use super::share_link;
+use super::surge::{Proxy, SurgeConfiguration};
+
+#[derive(Deserialize, Debug)]
+struct Sip008Server {
+  #[serde(default)]
+  remarks: Option<String>,
+  server: String,
+  server_port: u32,
+  password: String,
+  method: String,
+  #[serde(default)]
+  plugin: Option<String>,
+  #[serde(default)]
+  plugin_opts: Option<String>,
+}
+
+impl Sip008Server {
+  fn to_proxy(&self) -> Option<Proxy> {
+    let name = self
+      .remarks
+      .clone()
+      .filter(|remarks| !remarks.is_empty())
+      .unwrap_or_else(|| format!("{}:{}", self.server, self.server_port));
+    let mut proxy = Proxy::new(&name, ProxyProtocol::Ss, &self.server, self.server_port);
+    proxy.set_parameter("encrypt-method", &self.method);
+    proxy.set_parameter("password", &self.password);
+    if let Some(plugin) = self.plugin.as_ref().filter(|plugin| !plugin.is_empty()) {
+      let plugin = match &self.plugin_opts {
+        Some(opts) => format!("{};{}", plugin, opts),
+        None => plugin.clone(),
+      };
+      share_link::set_ss_plugin(&mut proxy, &plugin)?;
+    }
+    Some(proxy)
+  }
+}
+
+/// A Shadowsocks SIP008 online configuration document.
+#[derive(Deserialize, Debug)]
+pub struct Sip008Configuration {
+  servers: Vec<Sip008Server>,
+}
+
+impl Sip008Configuration {
+  pub fn from_config_string(config: &str) -> Option<Sip008Configuration> {
+    serde_json::from_str(config).ok()
+  }
+
+  pub fn to_surge_configuration(&self) -> SurgeConfiguration {
+    let mut surge_configuration = SurgeConfiguration::default();
+    for proxy in self.servers.iter().filter_map(|server| server.to_proxy()) {
+      surge_configuration.push_proxy(proxy);
+    }
+    surge_configuration
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::*;
+
+  #[test]
+  pub fn sip008_should_work() {
+    let configuration = Sip008Configuration::from_config_string(
+      r#"{
+  "version": 1,
+  "servers": [
+    {
+      "id": "27b8a625-4f4b-4428-9f0f-8a2317db7c79",
+      "remarks": "Name of the server",
+      "server": "example.com",
+      "server_port": 8388,
+      "password": "example",
+      "method": "chacha20-ietf-poly1305",
+      "plugin": "obfs-local",
+      "plugin_opts": "obfs=http;obfs-host=www.example.com"
+    },
+    {
+      "server": "v2ray.example.com",
+      "server_port": 443,
+      "password": "example",
+      "method": "aes-128-gcm",
+      "plugin": "v2ray-plugin"
+    }
+  ],
+  "bytes_used": 274877906944
+}"#,
+    )
+    .expect("Parsing should work");
+    let surge_configuration = configuration.to_surge_configuration();
+    assert_eq!(surge_configuration.get_proxies().len(), 1);
+    assert_eq!(
+      surge_configuration.get_proxies()[0].to_string(),
+      "Name of the server = ss,example.com,8388,encrypt-method=chacha20-ietf-poly1305,obfs=http,obfs-host=www.example.com,password=example"
+    );
+  }
+}
@@ -0,0 +1,182 @@
+use serde::{Deserialize, Serialize};
+
+use super::clash::ClashConfiguration;
+use super::quantumultx::{self, QuantumultXConfiguration};
+use super::share_link::ShareLinkSubscription;
+use super::sip008::Sip008Configuration;
+use super::surge::SurgeConfiguration;
+
+const SURGE_SECTIONS: &[&str] = &["[General]", "[Proxy]", "[Proxy Group]", "[Rule]"];
+const URI_SCHEMES: &[&str] = &["ss://", "vmess://", "trojan://", "socks://", "socks5://"];
+/// Formats `Auto` falls back to, in order, when the detected one finds no
+/// proxies.
+const FALLBACK_FORMATS: &[SourceFormat] = &[
+  SourceFormat::Surge,
+  SourceFormat::Clash,
+  SourceFormat::Base64Uri,
+  SourceFormat::Sip008,
+  SourceFormat::QuantumultX,
+];
+
+pub async fn fetch_text(url: &str) -> Option<String> {
+  match reqwest::get(url).await {
+    Ok(response) => response.text().await.ok(),
+    _ => None,
+  }
+}
+
+/// The format an airport publishes its subscription in.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum SourceFormat {
+  #[default]
+  Auto,
+  Surge,
+  Clash,
+  Base64Uri,
+  Sip008,
+  #[serde(rename = "quantumultx")]
+  QuantumultX,
+}
+
+fn is_base64(text: &str) -> bool {
+  !text.is_empty()
+    && text
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c.is_whitespace() || "+/=-_".contains(c))
+}
+
+impl SourceFormat {
+  /// Sniffs the format of a subscription body. Never returns `Auto`.
+  pub fn detect(text: &str) -> SourceFormat {
+    let trimmed = text.trim();
+    let lines: Vec<_> = trimmed.lines().map(|line| line.trim_end()).collect();
+    if trimmed.starts_with('{') {
+      SourceFormat::Sip008
+    } else if lines.iter().any(|line| line.starts_with("proxies:")) {
+      SourceFormat::Clash
+    } else if lines.iter().any(|line| SURGE_SECTIONS.contains(line)) {
+      SourceFormat::Surge
+    } else if lines
+      .iter()
+      .any(|line| *line == "[server_local]" || quantumultx::is_server_line(line))
+    {
+      SourceFormat::QuantumultX
+    } else if is_base64(trimmed)
+      || lines
+        .iter()
+        .any(|line| URI_SCHEMES.iter().any(|scheme| line.starts_with(scheme)))
+    {
+      SourceFormat::Base64Uri
+    } else {
+      SourceFormat::Surge
+    }
+  }
+
+  /// Parses a subscription body, returning the format that was actually used
+  /// alongside the result.
+  pub fn parse(self, text: &str) -> Option<(SourceFormat, SurgeConfiguration)> {
+    let configuration = match self {
+      SourceFormat::Auto => return SourceFormat::parse_any(text),
+      SourceFormat::Surge => SurgeConfiguration::from_config_string(text),
+      SourceFormat::Clash => ClashConfiguration::from_config_string(text)
+        .map(|configuration| configuration.to_surge_configuration()),
+      SourceFormat::Base64Uri => ShareLinkSubscription::from_config_string(text)
+        .map(|configuration| configuration.to_surge_configuration()),
+      SourceFormat::Sip008 => Sip008Configuration::from_config_string(text)
+        .map(|configuration| configuration.to_surge_configuration()),
+      SourceFormat::QuantumultX => QuantumultXConfiguration::from_config_string(text)
+        .map(|configuration| configuration.to_surge_configuration()),
+    };
+    configuration.map(|configuration| (self, configuration))
+  }
+
+  /// Tries the detected format first, then the others until one finds
+  /// proxies. If none does, the first successful parse is kept.
+  fn parse_any(text: &str) -> Option<(SourceFormat, SurgeConfiguration)> {
+    let detected = SourceFormat::detect(text);
+    let formats = FALLBACK_FORMATS.iter().filter(|format| **format != detected);
+    let mut first = None;
+    for format in std::iter::once(&detected).chain(formats) {
+      match format.parse(text) {
+        Some((format, configuration)) if !configuration.get_proxies().is_empty() => {
+          return Some((format, configuration))
+        }
+        result => first = first.or(result),
+      }
+    }
+    first
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::*;
+
+  #[test]
+  pub fn detect_should_work() {
+    assert_eq!(
+      SourceFormat::detect("#!MANAGED-CONFIG http://a.com\n\n[General]\nhttp-listen = 0.0.0.0:1234\n\n[Proxy]\n"),
+      SourceFormat::Surge
+    );
+    assert_eq!(
+      SourceFormat::detect("port: 7890\nproxies:\n  - { name: A, type: ss }\n"),
+      SourceFormat::Clash
+    );
+    assert_eq!(
+      SourceFormat::detect("c3M6Ly9ZV1Z6TFRFeU9DMW5ZMjA2ZEdWemRBQDE5Mi4xNjguMTAwLjE6ODg4OCNB\n"),
+      SourceFormat::Base64Uri
+    );
+    assert_eq!(
+      SourceFormat::detect("trojan://pwd@b.com:443#B\n"),
+      SourceFormat::Base64Uri
+    );
+    assert_eq!(
+      SourceFormat::detect(r#"{"version": 1, "servers": []}"#),
+      SourceFormat::Sip008
+    );
+    assert_eq!(
+      SourceFormat::detect("shadowsocks=a.com:443, method=aes-128-gcm, password=pwd, tag=A"),
+      SourceFormat::QuantumultX
+    );
+  }
+
+  #[test]
+  pub fn auto_parse_should_report_detected_format() {
+    let (format, configuration) = SourceFormat::Auto
+      .parse("proxies:\n  - { name: Proxy_1, type: trojan, server: a.com, port: 443, password: abc }\n")
+      .expect("Parsing should work");
+    assert_eq!(format, SourceFormat::Clash);
+    assert_eq!(configuration.get_proxies().len(), 1);
+    assert_eq!(configuration.get_proxies()[0].get_name(), "Proxy_1");
+  }
+
+  #[test]
+  pub fn auto_parse_should_fall_back_when_detection_is_wrong() {
+    let text = "[Proxy]\nA = trojan, a.com, 443, password=pwd\n\n[Notes]\nproxies: A\n";
+    assert_eq!(SourceFormat::detect(text), SourceFormat::Clash);
+    let (format, configuration) = SourceFormat::Auto.parse(text).expect("Parsing should work");
+    assert_eq!(format, SourceFormat::Surge);
+    assert_eq!(configuration.get_proxies()[0].get_name(), "A");
+  }
+
+  #[test]
+  pub fn explicit_format_should_not_sniff() {
+    assert!(SourceFormat::Clash
+      .parse("[Proxy]\nA = ss, a.com, 443, encrypt-method=abc, password=ddd")
+      .is_none());
+  }
+
+  #[test]
+  pub fn format_serialization_should_work() {
+    assert_eq!(
+      serde_json::to_string(&SourceFormat::Base64Uri).unwrap(),
+      "\"base64-uri\""
+    );
+    assert_eq!(
+      serde_json::from_str::<SourceFormat>("\"quantumultx\"").unwrap(),
+      SourceFormat::QuantumultX
+    );
+  }
+}
@@ -1,72 +1,150 @@
-use std::collections::BTreeMap;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
+
+use super::metadata::RenderMetadata;
+use super::mitm::Mitm;
+use super::protocol::ProxyProtocol;
+use super::rewrite;
+use super::rule;
+use super::script::Script;
+#[cfg(test)]
+use super::source::{fetch_text, SourceFormat};
+use super::tokenizer::{self, Field};
+use super::wireguard::WireGuardSection;
 
-fn params_map_from_strs(entries: &[&str]) -> BTreeMap<String, String> {
-  let mut ret = BTreeMap::new();
-  for entry in entries {
-    if entry.contains("=") {
-      if let [name, value] = &entry.split("=").collect::<Vec<_>>()[..] {
-        ret.insert(String::from(name.trim()), String::from(value.trim()));
-      }
-    }
+const BUILTIN_POLICY_TYPES: &[&str] = &["direct", "reject", "reject-tinygif", "reject-drop"];
+
+/// Parameters that identify the user on a server, next to the positional
+/// username and password.
+const CREDENTIAL_PARAMETERS: &[&str] = &["username", "password", "psk", "uuid", "token"];
+
+/// Test parameters of groups that do not set their own.
+pub const DEFAULT_TEST_URL: &str = "http://www.qualcomm.cn/generate_204";
+pub const DEFAULT_TEST_INTERVAL: u32 = 1800;
+
+fn is_section_head(line: &str) -> bool {
+  match line {
+    "[General]" | "[Proxy]" | "[Proxy Group]" | "[Rule]" | "[URL Rewrite]" => true,
+    l if l.starts_with("[") => true,
+    _ => false,
   }
-  ret
 }
 
-fn string_vec_from_strs(elems: &[&str]) -> Vec<String> {
-  elems
-    .iter()
-    .filter(|elem| !elem.contains("="))
-    .map(|elem| String::from(elem.trim()))
-    .collect()
+/// The domain of a `[Host]` entry such as `*.example.com = server:1.1.1.1`.
+fn host_key(host: &str) -> Option<&str> {
+  host
+    .split_once('=')
+    .map(|(domain, _)| domain.trim())
+    .filter(|domain| !domain.is_empty())
 }
 
-fn object_vec<T, F>(lines: &Vec<&str>, start: usize, transformer: F) -> (Vec<T>, usize)
-where
-  F: Fn(&str) -> Option<T>,
-{
-  let mut start = start + 1;
-  let mut ret = vec![];
-  while let Some(line) = lines.get(start) {
-    let line = line.trim();
-    if is_section_head(line) {
-      break;
-    }
-    start += 1;
-    if line.is_empty() || is_comment(line) {
-      continue;
-    } else if let Some(obj) = transformer(line) {
-      ret.push(obj);
-    }
-  }
-  (ret, start)
+/// Checks a `[Host]` entry the way the section is parsed.
+pub fn validate_host(host: &str) -> Result<(), String> {
+  match host.split_once('=') {
+    Some((_, address)) if host_key(host).is_some() && !address.trim().is_empty() => Ok(()),
+    _ => Err(String::from("expected `domain = address`")),
+  }
 }
 
-fn string_vec(lines: &Vec<&str>, start: usize) -> (Vec<String>, usize) {
-  let mut start = start + 1;
-  let mut ret = vec![];
-  while let Some(line) = lines.get(start) {
-    let line = line.trim();
-    if is_section_head(line) {
-      break;
-    }
-    start += 1;
-    if !line.is_empty() && !is_comment(line) {
-      ret.push(String::from(line));
+fn is_comment(line: &str) -> bool {
+  line.starts_with('#') || line.starts_with(';') || line.starts_with("//")
+}
+
+/// The sections the configuration understands. Everything else is kept
+/// verbatim in the layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Section {
+  General,
+  Proxy,
+  ProxyGroup,
+  Rule,
+  UrlRewrite,
+  Host,
+  Mitm,
+  Script,
+  HeaderRewrite,
+  BodyRewrite,
+  MapLocal,
+  /// A `[WireGuard <name>]` section, by its index in `wireguards`.
+  WireGuard(usize),
+}
+
+const SECTIONS: &[Section] = &[
+  Section::General,
+  Section::Proxy,
+  Section::ProxyGroup,
+  Section::Rule,
+  Section::UrlRewrite,
+  Section::Host,
+  Section::Mitm,
+  Section::Script,
+  Section::HeaderRewrite,
+  Section::BodyRewrite,
+  Section::MapLocal,
+];
+
+impl Section {
+  fn from_head(line: &str) -> Option<Section> {
+    SECTIONS.iter().copied().find(|section| section.head() == line)
+  }
+
+  fn name(self) -> &'static str {
+    match self {
+      Section::General => "General",
+      Section::Proxy => "Proxy",
+      Section::ProxyGroup => "Proxy Group",
+      Section::Rule => "Rule",
+      Section::UrlRewrite => "URL Rewrite",
+      Section::Host => "Host",
+      Section::Mitm => "MITM",
+      Section::Script => "Script",
+      Section::HeaderRewrite => "Header Rewrite",
+      Section::BodyRewrite => "Body Rewrite",
+      Section::MapLocal => "Map Local",
+      Section::WireGuard(_) => "WireGuard",
     }
   }
-  (ret, start)
-}
 
-fn is_section_head(line: &str) -> bool {
-  match line {
-    "[General]" | "[Proxy]" | "[Proxy Group]" | "[Rule]" | "[URL Rewrite]" => true,
-    l if l.starts_with("[") => true,
-    _ => false,
+  /// Sections that are only rendered when they have content.
+  fn is_optional(self) -> bool {
+    !matches!(
+      self,
+      Section::General | Section::Proxy | Section::ProxyGroup | Section::Rule
+    )
+  }
+
+  fn head(self) -> String {
+    format!("[{}]", self.name())
   }
 }
 
-fn is_comment(line: &str) -> bool {
-  line.starts_with("//")
+/// One line of a parsed profile, used to write it back the way it was read.
+#[derive(Debug, Clone)]
+enum LayoutLine {
+  /// Comments, blank lines, section heads, builtin policies, lines we could
+  /// not parse and the content of sections we do not model.
+  Raw(String),
+  /// The `#!MANAGED-CONFIG` line.
+  Head,
+  /// The `index`-th entry of a known section. `raw` is written back as long
+  /// as the entry still renders to `canonical`. For `[MITM]` the index is
+  /// the position of the key.
+  Item {
+    section: Section,
+    index: usize,
+    raw: String,
+    canonical: String,
+  },
+  /// Where entries added to a section after parsing go.
+  SectionEnd(Section),
+}
+
+/// What became of a line inside a known section.
+enum ParsedLine {
+  Item { index: usize, canonical: String },
+  Verbatim,
+  Rejected,
 }
 
 #[derive(Debug, Clone)]
@@ -77,79 +155,222 @@ pub struct SurgeConfiguration {
   proxy_groups: Vec<ProxyGroup>,
   rules: Vec<String>,
   url_rewrites: Vec<String>,
+  hosts: Vec<String>,
+  mitm: Mitm,
+  scripts: Vec<Script>,
+  header_rewrites: Vec<String>,
+  body_rewrites: Vec<String>,
+  map_locals: Vec<String>,
+  wireguards: Vec<WireGuardSection>,
+  diagnostics: Vec<Diagnostic>,
+  metadata: RenderMetadata,
+  layout: Option<Vec<LayoutLine>>,
+}
+
+/// An upstream line that could not be used, and why. Proxies built by the
+/// subscription converters have no line number.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+  line_number: Option<usize>,
+  section: &'static str,
+  line: String,
+  reason: String,
+}
+
+impl Diagnostic {
+  fn new(line_number: Option<usize>, section: Section, line: &str, reason: String) -> Diagnostic {
+    Diagnostic {
+      line_number,
+      section: section.name(),
+      line: String::from(line),
+      reason,
+    }
+  }
+
+  #[cfg(test)]
+  pub fn get_reason(&self) -> &str {
+    &self.reason
+  }
 }
 
 #[derive(Debug, Clone)]
 pub struct Proxy {
   name: String,
-  proto: String,
+  proto: ProxyProtocol,
   host: String,
   port: u32,
   username: Option<String>,
   password: Option<String>,
   parameters: BTreeMap<String, String>,
+  /// The airport the proxy came from. Only used to filter proxies into
+  /// groups, never rendered.
+  airport: Option<String>,
 }
 
 impl Proxy {
-  fn from_strs(
-    name: &str,
-    proto: &str,
-    host: &str,
-    port_str: &str,
-    param_strs: &[&str],
-  ) -> Option<Proxy> {
-    let mut param_strs = param_strs;
-    let username = if !param_strs[0].contains("=") {
-      Some(String::from(param_strs[0].trim()))
-    } else {
-      None
+  pub fn new(name: &str, proto: ProxyProtocol, host: &str, port: u32) -> Proxy {
+    Proxy {
+      name: String::from(name),
+      proto,
+      host: String::from(host),
+      port,
+      username: None,
+      password: None,
+      parameters: BTreeMap::new(),
+      airport: None,
+    }
+  }
+
+  pub fn set_credentials(&mut self, username: &str, password: &str) {
+    self.username = Some(String::from(username));
+    self.password = Some(String::from(password));
+  }
+
+  pub fn set_parameter(&mut self, name: &str, value: &str) {
+    self.parameters.insert(String::from(name), String::from(value));
+  }
+
+  pub fn validate(&self) -> Result<(), String> {
+    self.proto.validate(&self.parameters)
+  }
+
+  fn from_name_definition(name: &str, definition: &str) -> Result<Proxy, String> {
+    let fields = tokenizer::tokenize(definition);
+    let (proto, fields) = match &fields[..] {
+      [Field::Positional(proto), fields @ ..] => (
+        ProxyProtocol::from_str(proto)
+          .ok_or_else(|| format!("unsupported protocol `{}`", proto))?,
+        fields,
+      ),
+      _ => return Err(String::from("missing protocol")),
     };
-    let password = if username.is_some() {
-      Some(String::from(param_strs[1].trim()))
+    let (host, port, fields) = if proto.has_endpoint() {
+      match fields {
+        [Field::Positional(host), Field::Positional(port_str), fields @ ..] => {
+          match port_str.parse::<u32>() {
+            Ok(port) => (host.clone(), port, fields),
+            _ => return Err(format!("invalid port `{}`", port_str)),
+          }
+        }
+        _ => return Err(String::from("missing host or port")),
+      }
     } else {
-      None
+      (String::new(), 0, fields)
     };
-    param_strs = if password.is_some() {
-      &param_strs[2..]
-    } else {
-      param_strs
+    let (username, password, fields) = match fields {
+      [Field::Positional(username), Field::Positional(password), fields @ ..]
+        if proto.has_credentials() =>
+      {
+        (Some(username.clone()), Some(password.clone()), fields)
+      }
+      _ => (None, None, fields),
     };
 
-    match port_str.trim().parse::<u32>() {
-      Ok(port) => Some(Proxy {
-        name: String::from(name.trim()),
-        proto: String::from(proto.trim()),
-        host: String::from(host.trim()),
-        port,
-        username,
-        password,
-        parameters: params_map_from_strs(param_strs),
-      }),
-      _ => None,
+    let proxy = Proxy {
+      name: String::from(name.trim()),
+      proto,
+      host,
+      port,
+      username,
+      password,
+      parameters: tokenizer::named(fields),
+      airport: None,
+    };
+    proxy.validate()?;
+    Ok(proxy)
+  }
+
+  fn from_str(proxy_str: &str) -> Result<Proxy, String> {
+    let components: Vec<_> = proxy_str.splitn(2, "=").collect();
+    match &components[..] {
+      [name, definition] => Proxy::from_name_definition(name, definition),
+      _ => Err(String::from("expected `name = definition`")),
     }
   }
 
-  fn from_name_definition(name: &str, definition: &str) -> Option<Proxy> {
-    let def_parts: Vec<_> = definition.split(",").collect();
-    if def_parts.len() < 3 {
+  /// `DIRECT = direct` and friends are emitted by the renderer itself.
+  fn is_builtin_policy(proxy_str: &str) -> bool {
+    proxy_str
+      .split_once('=')
+      .map(|(_, definition)| {
+        matches!(
+          tokenizer::tokenize(definition).first(),
+          Some(Field::Positional(proto)) if BUILTIN_POLICY_TYPES.contains(&proto.as_str())
+        )
+      })
+      .unwrap_or(false)
+  }
+
+  pub fn get_name(&self) -> &str {
+    &self.name
+  }
+
+  pub fn set_name(&mut self, name: &str) {
+    self.name = String::from(name);
+  }
+
+  pub fn get_proto(&self) -> &str {
+    self.proto.as_str()
+  }
+
+  pub fn get_host(&self) -> &str {
+    &self.host
+  }
+
+  pub fn get_port(&self) -> u32 {
+    self.port
+  }
+
+  pub fn get_username(&self) -> Option<&str> {
+    self.username.as_deref()
+  }
+
+  pub fn get_password(&self) -> Option<&str> {
+    self.password.as_deref()
+  }
+
+  pub fn get_parameter(&self, name: &str) -> Option<&str> {
+    self.parameters.get(name).map(|value| value.as_str())
+  }
+
+  /// Proxies with the same key reach the same server as the same user.
+  /// Proxies without an endpoint, such as WireGuard, have none.
+  pub fn server_key(&self) -> Option<String> {
+    if !self.proto.has_endpoint() {
       return None;
     }
-    match &def_parts[0..3] {
-      [proto, host, port_str] => Proxy::from_strs(name, *proto, *host, *port_str, &def_parts[3..]),
-      _ => None,
-    }
+    let credentials: Vec<_> = CREDENTIAL_PARAMETERS
+      .iter()
+      .map(|name| self.get_parameter(name))
+      .collect();
+    Some(format!(
+      "{:?}",
+      (
+        self.proto,
+        self.host.to_lowercase(),
+        self.port,
+        &self.username,
+        &self.password,
+        credentials
+      )
+    ))
   }
 
-  fn from_str(proxy_str: &str) -> Option<Proxy> {
-    let components: Vec<_> = proxy_str.splitn(2, "=").collect();
-    match &components[..] {
-      [name, definition] => Proxy::from_name_definition(*name, *definition),
-      _ => None,
+  /// Whether the proxy points at a host no server can be behind, such as
+  /// `127.0.0.1` or `0.0.0.0`.
+  pub fn has_unroutable_host(&self) -> bool {
+    if !self.proto.has_endpoint() {
+      return false;
+    }
+    match self.host.parse::<IpAddr>() {
+      Ok(IpAddr::V4(ip)) => ip.is_loopback() || ip.is_unspecified() || ip.is_broadcast(),
+      Ok(IpAddr::V6(ip)) => ip.is_loopback() || ip.is_unspecified(),
+      Err(_) => self.host.eq_ignore_ascii_case("localhost"),
     }
   }
 
-  pub fn get_name(&self) -> &str {
-    &self.name
+  pub fn get_airport(&self) -> Option<&str> {
+    self.airport.as_deref()
   }
 }
 
@@ -157,17 +378,19 @@ impl ToString for Proxy {
   fn to_string(&self) -> String {
     let mut ret = String::new();
     let mut definition_parts: Vec<String> = vec![];
-    definition_parts.push(self.proto.clone());
-    definition_parts.push(self.host.clone());
-    definition_parts.push(self.port.to_string());
+    definition_parts.push(String::from(self.proto.as_str()));
+    if self.proto.has_endpoint() {
+      definition_parts.push(self.host.clone());
+      definition_parts.push(self.port.to_string());
+    }
     if let Some(ref username_str) = &self.username {
-      definition_parts.push(username_str.clone());
+      definition_parts.push(tokenizer::quote_positional(username_str));
     }
     if let Some(ref password_str) = &self.password {
-      definition_parts.push(password_str.clone());
+      definition_parts.push(tokenizer::quote_positional(password_str));
     }
     for (name, value) in &self.parameters {
-      definition_parts.push([name, "=", value].concat());
+      definition_parts.push([name, "=", &tokenizer::quote(value)].concat());
     }
 
     ret.push_str(&self.name);
@@ -186,66 +409,243 @@ pub enum ProxyGroupType {
     tolerance: u32,
     timeout: u32,
   },
+  Fallback {
+    url: String,
+    interval: u32,
+    timeout: u32,
+  },
+  LoadBalance {
+    url: String,
+    interval: u32,
+    timeout: u32,
+    persistent: bool,
+  },
+  /// Picks a policy by network, e.g. `"SSID:Home" = DIRECT`. The group has
+  /// no members of its own.
+  Subnet {
+    default: String,
+    mappings: Vec<(String, String)>,
+  },
+  Smart,
 }
 
 impl Default for ProxyGroupType {
   fn default() -> Self {
-    ProxyGroupType::UrlTest {
-      url: String::from("http://www.qualcomm.cn/generate_204"),
-      interval: 1800,
-      tolerance: 200,
-      timeout: 5
-    }
+    ProxyGroupType::with_defaults("url-test").unwrap()
   }
 }
 
 impl ProxyGroupType {
-
-  fn from_str(type_str: &str, params_map: &BTreeMap<String, String>) -> Option<ProxyGroupType> {
-    match type_str.trim() {
+  /// A group of the given type using the test parameters of the groups we
+  /// build ourselves. Subnet groups need a default policy, so there is none.
+  pub fn with_defaults(type_str: &str) -> Option<ProxyGroupType> {
+    let url = String::from(DEFAULT_TEST_URL);
+    let (interval, timeout) = (DEFAULT_TEST_INTERVAL, 5);
+    match type_str {
       "select" => Some(ProxyGroupType::Select),
       "url-test" => Some(ProxyGroupType::UrlTest {
-        url: params_map
-          .get("url")
-          .map(|s| s.clone())
-          .unwrap_or(String::from("www.google.com")),
-        interval: params_map
-          .get("interval")
-          .and_then(|s| s.parse::<u32>().ok())
-          .unwrap_or(600),
-        tolerance: params_map
-          .get("tolerance")
-          .and_then(|s| s.parse::<u32>().ok())
-          .unwrap_or(100),
-        timeout: params_map
-          .get("timeout")
-          .and_then(|s| s.parse::<u32>().ok())
-          .unwrap_or(5),
+        url,
+        interval,
+        tolerance: 200,
+        timeout,
+      }),
+      "fallback" => Some(ProxyGroupType::Fallback {
+        url,
+        interval,
+        timeout,
+      }),
+      "load-balance" => Some(ProxyGroupType::LoadBalance {
+        url,
+        interval,
+        timeout,
+        persistent: false,
       }),
+      "smart" => Some(ProxyGroupType::Smart),
       _ => None,
     }
   }
-}
 
-impl ToString for ProxyGroupType {
-  fn to_string(&self) -> String {
-    let mut ret = String::new();
-    if let ProxyGroupType::UrlTest {
-      url,
-      interval,
-      tolerance,
-      timeout,
-    } = self
-    {
-      ret.push_str(&format!(
-        "url={url},interval={interval},tolerance={tolerance},timeout={timeout}",
-        url = url,
-        interval = interval,
-        tolerance = tolerance,
-        timeout = timeout
-      ));
+  fn from_fields(type_str: &str, fields: &[Field]) -> Result<ProxyGroupType, String> {
+    let params_map = tokenizer::named(fields);
+    let url = || {
+      params_map
+        .get("url")
+        .cloned()
+        .unwrap_or_else(|| String::from("www.google.com"))
+    };
+    let number = |name: &str, default: u32| {
+      params_map
+        .get(name)
+        .and_then(|s| s.parse::<u32>().ok())
+        .unwrap_or(default)
+    };
+    match type_str.trim() {
+      "select" => Ok(ProxyGroupType::Select),
+      "url-test" => Ok(ProxyGroupType::UrlTest {
+        url: url(),
+        interval: number("interval", 600),
+        tolerance: number("tolerance", 100),
+        timeout: number("timeout", 5),
+      }),
+      "fallback" => Ok(ProxyGroupType::Fallback {
+        url: url(),
+        interval: number("interval", 600),
+        timeout: number("timeout", 5),
+      }),
+      "load-balance" => Ok(ProxyGroupType::LoadBalance {
+        url: url(),
+        interval: number("interval", 600),
+        timeout: number("timeout", 5),
+        persistent: params_map
+          .get("persistent")
+          .map(|persistent| persistent == "true" || persistent == "1")
+          .unwrap_or(false),
+      }),
+      "subnet" => {
+        let mappings: Vec<_> = fields
+          .iter()
+          .filter_map(|field| match field {
+            Field::Named(condition, policy) if condition.contains(':') => {
+              Some((condition.clone(), policy.clone()))
+            }
+            _ => None,
+          })
+          .collect();
+        match params_map.get("default") {
+          Some(default) => Ok(ProxyGroupType::Subnet {
+            default: default.clone(),
+            mappings,
+          }),
+          None => Err(String::from("subnet group is missing `default`")),
+        }
+      }
+      "smart" => Ok(ProxyGroupType::Smart),
+      _ => Err(format!("unsupported group type `{}`", type_str)),
+    }
+  }
+
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      ProxyGroupType::Select => "select",
+      ProxyGroupType::UrlTest { .. } => "url-test",
+      ProxyGroupType::Fallback { .. } => "fallback",
+      ProxyGroupType::LoadBalance { .. } => "load-balance",
+      ProxyGroupType::Subnet { .. } => "subnet",
+      ProxyGroupType::Smart => "smart",
+    }
+  }
+
+  /// Only load-balance groups can be persistent.
+  pub fn set_persistent(&mut self, value: bool) {
+    if let ProxyGroupType::LoadBalance { persistent, .. } = self {
+      *persistent = value;
+    }
+  }
+
+  /// Replaces the test parameters that are given. Groups that do not test
+  /// their members are left alone, and only url-test has a tolerance.
+  pub fn set_test_parameters(
+    &mut self,
+    url: Option<&str>,
+    interval: Option<u32>,
+    tolerance: Option<u32>,
+    timeout: Option<u32>,
+  ) {
+    let (current_url, current_interval, current_timeout) = match self {
+      ProxyGroupType::UrlTest {
+        url,
+        interval,
+        tolerance: current_tolerance,
+        timeout,
+      } => {
+        *current_tolerance = tolerance.unwrap_or(*current_tolerance);
+        (url, interval, timeout)
+      }
+      ProxyGroupType::Fallback {
+        url,
+        interval,
+        timeout,
+      }
+      | ProxyGroupType::LoadBalance {
+        url,
+        interval,
+        timeout,
+        ..
+      } => (url, interval, timeout),
+      ProxyGroupType::Select | ProxyGroupType::Subnet { .. } | ProxyGroupType::Smart => return,
+    };
+    if let Some(url) = url {
+      *current_url = String::from(url);
+    }
+    *current_interval = interval.unwrap_or(*current_interval);
+    *current_timeout = timeout.unwrap_or(*current_timeout);
+  }
+
+  /// Whether `key` is one of the parameters this type is parsed from.
+  fn uses_parameter(&self, key: &str) -> bool {
+    match self {
+      ProxyGroupType::Select | ProxyGroupType::Smart => false,
+      ProxyGroupType::UrlTest { .. } => ["url", "interval", "tolerance", "timeout"].contains(&key),
+      ProxyGroupType::Fallback { .. } => ["url", "interval", "timeout"].contains(&key),
+      ProxyGroupType::LoadBalance { .. } => {
+        ["url", "interval", "timeout", "persistent"].contains(&key)
+      }
+      ProxyGroupType::Subnet { .. } => key == "default" || key.contains(':'),
+    }
+  }
+
+  /// The `key=value` fields rendered after the members of the group.
+  fn parameters(&self) -> Vec<String> {
+    match self {
+      ProxyGroupType::Select | ProxyGroupType::Smart => vec![],
+      ProxyGroupType::UrlTest {
+        url,
+        interval,
+        tolerance,
+        timeout,
+      } => vec![
+        format!("url={}", tokenizer::quote(url)),
+        format!("interval={}", interval),
+        format!("tolerance={}", tolerance),
+        format!("timeout={}", timeout),
+      ],
+      ProxyGroupType::Fallback {
+        url,
+        interval,
+        timeout,
+      } => vec![
+        format!("url={}", tokenizer::quote(url)),
+        format!("interval={}", interval),
+        format!("timeout={}", timeout),
+      ],
+      ProxyGroupType::LoadBalance {
+        url,
+        interval,
+        timeout,
+        persistent,
+      } => {
+        let mut parameters = vec![
+          format!("url={}", tokenizer::quote(url)),
+          format!("interval={}", interval),
+          format!("timeout={}", timeout),
+        ];
+        if *persistent {
+          parameters.push(String::from("persistent=true"));
+        }
+        parameters
+      }
+      ProxyGroupType::Subnet { default, mappings } => {
+        let mut parameters = vec![format!("default={}", tokenizer::quote(default))];
+        for (condition, policy) in mappings {
+          parameters.push(format!(
+            "{}={}",
+            tokenizer::quote_positional(condition),
+            tokenizer::quote(policy)
+          ));
+        }
+        parameters
+      }
     }
-    ret
   }
 }
 
@@ -254,6 +654,9 @@ pub struct ProxyGroup {
   name: String,
   group_type: ProxyGroupType,
   proxy_names: Vec<String>,
+  /// Parameters that do not belong to the group type, such as `hidden` and
+  /// `no-alert`. Rendered after the type parameters.
+  options: BTreeMap<String, String>,
 }
 
 impl ProxyGroup {
@@ -261,10 +664,15 @@ impl ProxyGroup {
     ProxyGroup {
       name: String::from(name),
       group_type: ProxyGroupType::default(),
-      proxy_names: vec![]
+      proxy_names: vec![],
+      options: BTreeMap::new(),
     }
   }
 
+  pub fn set_option(&mut self, name: &str, value: &str) {
+    self.options.insert(String::from(name), String::from(value));
+  }
+
   pub fn set_type(&mut self, proxy_type: ProxyGroupType) {
     self.group_type = proxy_type;
   }
@@ -273,52 +681,84 @@ impl ProxyGroup {
     self.proxy_names.push(String::from(name));
   }
 
-  #[cfg(test)]
+  pub fn get_name(&self) -> &str {
+    &self.name
+  }
+
+  pub fn get_type(&self) -> &ProxyGroupType {
+    &self.group_type
+  }
+
   pub fn get_proxies(&self) -> &Vec<String> {
     &self.proxy_names
   }
 
-  fn from_name_definition(name: &str, definition: &str) -> Option<ProxyGroup> {
-    let components: Vec<_> = definition.split(",").collect();
-    let params_map = params_map_from_strs(&components[..]);
-    components
-      .get(0)
-      .and_then(|type_str| ProxyGroupType::from_str(type_str.trim(), &params_map))
-      .map(|group_type| ProxyGroup {
-        name: String::from(name.trim()),
-        group_type: group_type,
-        proxy_names: string_vec_from_strs(&components[1..]),
-      })
+  /// Replaces `name` in the group, if it is there.
+  pub fn rename_proxy(&mut self, name: &str, new_name: &str) {
+    for proxy_name in &mut self.proxy_names {
+      if proxy_name == name {
+        *proxy_name = String::from(new_name);
+      }
+    }
   }
 
-  fn from_str(proxy_group: &str) -> Option<ProxyGroup> {
-    let components: Vec<_> = proxy_group.splitn(2, "=").collect();
-    match &components[..] {
-      [name, definition] => ProxyGroup::from_name_definition(name.trim(), *definition),
-      _ => None,
+  /// Whether Surge would find no policy in the group.
+  pub fn is_empty(&self) -> bool {
+    self.proxy_names.is_empty()
+      && !self.options.contains_key("include-all-proxies")
+      && !self.options.contains_key("include-other-group")
+      && !matches!(self.group_type, ProxyGroupType::Subnet { .. })
+  }
+
+  fn quoted_proxy_names(&self) -> String {
+    self
+      .proxy_names
+      .iter()
+      .map(|name| tokenizer::quote_positional(name))
+      .collect::<Vec<_>>()
+      .join(",")
+  }
+
+  fn from_name_definition(name: &str, definition: &str) -> Result<ProxyGroup, String> {
+    let fields = tokenizer::tokenize(definition);
+    match &fields[..] {
+      [Field::Positional(type_str), fields @ ..] => {
+        ProxyGroupType::from_fields(type_str, fields).map(|group_type| {
+          let options = tokenizer::named(fields)
+            .into_iter()
+            .filter(|(key, _)| !group_type.uses_parameter(key))
+            .collect();
+          ProxyGroup {
+            name: String::from(name.trim()),
+            group_type,
+            proxy_names: tokenizer::positional(fields),
+            options,
+          }
+        })
+      }
+      _ => Err(String::from("missing group type")),
+    }
+  }
+
+  fn from_str(proxy_group: &str) -> Result<ProxyGroup, String> {
+    match proxy_group.split_once('=') {
+      Some((name, definition)) => ProxyGroup::from_name_definition(name, definition),
+      None => Err(String::from("expected `name = definition`")),
     }
   }
 }
 
 impl ToString for ProxyGroup {
   fn to_string(&self) -> String {
-    let mut ret = String::new();
-
-    ret.push_str(&self.name);
-    ret.push_str(" = ");
-    match &self.group_type {
-      ProxyGroupType::Select => {
-        ret.push_str("select,");
-        ret.push_str(&self.proxy_names.join(","));
-      }
-      url_test @ ProxyGroupType::UrlTest { .. } => {
-        ret.push_str("url-test,");
-        ret.push_str(&self.proxy_names.join(","));
-        ret.push_str(",");
-        ret.push_str(&url_test.to_string());
-      }
+    let mut definition_parts = vec![String::from(self.group_type.as_str())];
+    if !self.proxy_names.is_empty() {
+      definition_parts.push(self.quoted_proxy_names());
     }
-    ret
+    definition_parts.extend(self.group_type.parameters());
+    for (name, value) in &self.options {
+      definition_parts.push([name, "=", &tokenizer::quote(value)].concat());
+    }
+    format!("{} = {}", self.name, definition_parts.join(","))
   }
 }
 
@@ -331,65 +771,337 @@ impl Default for SurgeConfiguration {
       proxy_groups: vec![],
       rules: vec![],
       url_rewrites: vec![],
+      hosts: vec![],
+      mitm: Mitm::default(),
+      scripts: vec![],
+      header_rewrites: vec![],
+      body_rewrites: vec![],
+      map_locals: vec![],
+      wireguards: vec![],
+      diagnostics: vec![],
+      metadata: RenderMetadata::default(),
+      layout: None,
     }
   }
 }
 
 impl SurgeConfiguration {
+  /// Airports are fetched through their configured `SourceFormat` instead,
+  /// this one always detects the format.
+  #[cfg(test)]
   pub async fn from_url(url: &str) -> Option<SurgeConfiguration> {
-    match reqwest::get(url).await {
-      Ok(response) => match response.text().await {
-        Ok(text) => SurgeConfiguration::from_config_string(&text),
-        _ => None,
-      },
-      _ => None,
-    }
+    SurgeConfiguration::from_text(&fetch_text(url).await?)
+  }
+
+  /// Parses a subscription body in whichever of the `SourceFormat`s it is
+  /// written in.
+  #[cfg(test)]
+  pub fn from_text(text: &str) -> Option<SurgeConfiguration> {
+    SourceFormat::Auto
+      .parse(text)
+      .map(|(_, configuration)| configuration)
   }
 
-  fn from_config_string(config: &str) -> Option<SurgeConfiguration> {
-    let lines: Vec<&str> = config.split("\n").collect();
-    let mut current_line_number = 0;
+  /// Parses a profile, remembering its layout so that `to_string` gives back
+  /// the same text when nothing was changed.
+  pub fn from_config_string(config: &str) -> Option<SurgeConfiguration> {
     let mut configuration = SurgeConfiguration::default();
-    while let Some(line) = lines.get(current_line_number) {
-      match line {
-        &"[General]" => {
-          let (general, next_line) = string_vec(&lines, current_line_number);
-          configuration.general = general;
-          current_line_number = next_line;
+    let mut layout = vec![];
+    let mut seen_section = false;
+    let mut section: Option<Section> = None;
+    // Position right after the last non-blank line of the current section.
+    let mut section_end = 0;
+    for (line_number, line) in config.split('\n').enumerate() {
+      let raw = line.strip_suffix('\r').unwrap_or(line);
+      let trimmed = raw.trim();
+      if is_section_head(trimmed) {
+        if let Some(section) = section {
+          layout.insert(section_end, LayoutLine::SectionEnd(section));
         }
-        &"[Proxy]" => {
-          let (proxies, next_line) =
-            object_vec(&lines, current_line_number, |line| Proxy::from_str(line));
-          configuration.proxies = proxies;
-          current_line_number = next_line;
+        seen_section = true;
+        section = Section::from_head(trimmed).or_else(|| {
+          WireGuardSection::from_head(trimmed).map(|wireguard| {
+            configuration.wireguards.push(wireguard);
+            Section::WireGuard(configuration.wireguards.len() - 1)
+          })
+        });
+        layout.push(LayoutLine::Raw(String::from(raw)));
+        section_end = layout.len();
+        continue;
+      }
+      if !seen_section && trimmed.starts_with("#!") && configuration.head.is_empty() {
+        configuration.head = String::from(trimmed);
+        layout.push(LayoutLine::Head);
+        continue;
+      }
+      match section {
+        Some(section) if !trimmed.is_empty() && !is_comment(trimmed) => {
+          match configuration.parse_line(section, line_number + 1, trimmed) {
+            ParsedLine::Item { index, canonical } => layout.push(LayoutLine::Item {
+              section,
+              index,
+              raw: String::from(raw),
+              canonical,
+            }),
+            // Rejected lines are reported and written back as they were.
+            ParsedLine::Verbatim | ParsedLine::Rejected => {
+              layout.push(LayoutLine::Raw(String::from(raw)))
+            }
+          }
         }
-        &"[Proxy Group]" => {
-          let (proxy_groups, next_line) = object_vec(&lines, current_line_number, |line| {
-            ProxyGroup::from_str(line)
-          });
-          configuration.proxy_groups = proxy_groups;
-          current_line_number = next_line;
+        _ => layout.push(LayoutLine::Raw(String::from(raw))),
+      }
+      if !trimmed.is_empty() {
+        section_end = layout.len();
+      }
+    }
+    if let Some(section) = section {
+      layout.insert(section_end, LayoutLine::SectionEnd(section));
+    }
+    configuration.layout = Some(layout);
+    Some(configuration)
+  }
+
+  /// Adds one entry of a known section, recording a diagnostic for lines
+  /// that cannot be used.
+  fn parse_line(&mut self, section: Section, line_number: usize, line: &str) -> ParsedLine {
+    let (index, canonical) = match section {
+      Section::General => {
+        self.general.push(String::from(line));
+        (self.general.len() - 1, String::from(line))
+      }
+      Section::Proxy => {
+        if Proxy::is_builtin_policy(line) {
+          return ParsedLine::Verbatim;
         }
-        &"[Rule]" => {
-          let (rules, next_line) = string_vec(&lines, current_line_number);
-          configuration.rules = rules;
-          current_line_number = next_line;
+        match Proxy::from_str(line) {
+          Ok(proxy) => {
+            let canonical = proxy.to_string();
+            self.report_unknown_parameters(Some(line_number), line, &proxy);
+            self.proxies.push(proxy);
+            (self.proxies.len() - 1, canonical)
+          }
+          Err(reason) => {
+            self
+              .diagnostics
+              .push(Diagnostic::new(Some(line_number), section, line, reason));
+            return ParsedLine::Rejected;
+          }
         }
-        &"[URL Rewrite]" => {
-          let (url_rewrites, next_line) = string_vec(&lines, current_line_number);
-          configuration.url_rewrites = url_rewrites;
-          current_line_number = next_line;
+      }
+      Section::ProxyGroup => match ProxyGroup::from_str(line) {
+        Ok(proxy_group) => {
+          let canonical = proxy_group.to_string();
+          self.proxy_groups.push(proxy_group);
+          (self.proxy_groups.len() - 1, canonical)
+        }
+        // The group cannot be used, but is still valid Surge, so it is kept
+        // when the profile is written back.
+        Err(reason) => {
+          self
+            .diagnostics
+            .push(Diagnostic::new(Some(line_number), section, line, reason));
+          return ParsedLine::Verbatim;
         }
-        l if l.starts_with("#!") => {
-          configuration.head = String::from(*l);
-          current_line_number = current_line_number + 1;
+      },
+      Section::Rule => {
+        self.rules.push(String::from(line));
+        (self.rules.len() - 1, String::from(line))
+      }
+      Section::UrlRewrite => {
+        self.url_rewrites.push(String::from(line));
+        (self.url_rewrites.len() - 1, String::from(line))
+      }
+      Section::Host => {
+        if let Err(reason) = validate_host(line) {
+          self
+            .diagnostics
+            .push(Diagnostic::new(Some(line_number), section, line, reason));
+          return ParsedLine::Rejected;
         }
-        _ => {
-          current_line_number = current_line_number + 1;
+        self.hosts.push(String::from(line));
+        (self.hosts.len() - 1, String::from(line))
+      }
+      Section::Mitm => {
+        let result = match line.split_once('=') {
+          Some((key, value)) => self.mitm.set(key.trim(), value.trim()),
+          None => Err(String::from("expected `key = value`")),
+        };
+        match result {
+          Ok(Some(index)) => (index, self.mitm.lines()[index].clone()),
+          Ok(None) => return ParsedLine::Verbatim,
+          Err(reason) => {
+            self
+              .diagnostics
+              .push(Diagnostic::new(Some(line_number), section, line, reason));
+            return ParsedLine::Rejected;
+          }
         }
       }
+      Section::Script => match Script::from_str(line) {
+        Ok(script) => {
+          let canonical = script.to_string();
+          self.scripts.push(script);
+          (self.scripts.len() - 1, canonical)
+        }
+        Err(reason) => {
+          self
+            .diagnostics
+            .push(Diagnostic::new(Some(line_number), section, line, reason));
+          return ParsedLine::Rejected;
+        }
+      },
+      Section::HeaderRewrite | Section::BodyRewrite | Section::MapLocal => {
+        let result = match section {
+          Section::HeaderRewrite => rewrite::validate_header_rewrite(line),
+          Section::BodyRewrite => rewrite::validate_body_rewrite(line),
+          _ => rewrite::validate_map_local(line),
+        };
+        if let Err(reason) = result {
+          self
+            .diagnostics
+            .push(Diagnostic::new(Some(line_number), section, line, reason));
+          return ParsedLine::Rejected;
+        }
+        let lines = match section {
+          Section::HeaderRewrite => &mut self.header_rewrites,
+          Section::BodyRewrite => &mut self.body_rewrites,
+          _ => &mut self.map_locals,
+        };
+        lines.push(String::from(line));
+        (lines.len() - 1, String::from(line))
+      }
+      Section::WireGuard(wireguard) => match self.wireguards[wireguard].push_entry(line) {
+        Ok(index) => (index, self.wireguards[wireguard].lines()[index].clone()),
+        Err(reason) => {
+          self
+            .diagnostics
+            .push(Diagnostic::new(Some(line_number), section, line, reason));
+          return ParsedLine::Rejected;
+        }
+      },
+    };
+    ParsedLine::Item { index, canonical }
+  }
+
+  /// The known sections followed by one entry per `[WireGuard <name>]`
+  /// section.
+  fn sections(&self) -> Vec<Section> {
+    SECTIONS
+      .iter()
+      .copied()
+      .chain((0..self.wireguards.len()).map(Section::WireGuard))
+      .collect()
+  }
+
+  fn section_items(&self, section: Section) -> Vec<String> {
+    fn render<T: ToString>(items: &[T]) -> Vec<String> {
+      items.iter().map(|item| item.to_string()).collect()
+    }
+    match section {
+      Section::General => self.general.clone(),
+      Section::Proxy => render(&self.proxies),
+      Section::ProxyGroup => render(&self.proxy_groups),
+      Section::Rule => self.rules.clone(),
+      Section::UrlRewrite => self.url_rewrites.clone(),
+      Section::Host => self.hosts.clone(),
+      Section::Mitm => self.mitm.lines(),
+      Section::Script => render(&self.scripts),
+      Section::HeaderRewrite => self.header_rewrites.clone(),
+      Section::BodyRewrite => self.body_rewrites.clone(),
+      Section::MapLocal => self.map_locals.clone(),
+      Section::WireGuard(wireguard) => self.wireguards[wireguard].lines(),
+    }
+  }
+
+  /// Whether the section has nothing to render. `[MITM]` has a line per key
+  /// even when the key is not set.
+  fn is_section_empty(&self, section: Section) -> bool {
+    match section {
+      Section::Mitm => self.mitm.is_empty(),
+      _ => self.section_items(section).iter().all(|item| item.is_empty()),
     }
-    Some(configuration)
+  }
+
+  fn section_as_string(&self, section: Section) -> String {
+    match section {
+      Section::General => self.general_as_string(),
+      Section::Proxy => self.proxy_as_string(),
+      Section::ProxyGroup => self.proxy_group_as_string(),
+      Section::Rule => self.rule_as_string(),
+      Section::UrlRewrite => self.url_rewrite_as_string(),
+      Section::Host => self.host_as_string(),
+      Section::Mitm => self.mitm_as_string(),
+      Section::Script => self.script_as_string(),
+      Section::HeaderRewrite => {
+        SurgeConfiguration::vec_as_string("[Header Rewrite]", &self.header_rewrites)
+      }
+      Section::BodyRewrite => SurgeConfiguration::vec_as_string("[Body Rewrite]", &self.body_rewrites),
+      Section::MapLocal => SurgeConfiguration::vec_as_string("[Map Local]", &self.map_locals),
+      Section::WireGuard(wireguard) => {
+        let wireguard = &self.wireguards[wireguard];
+        SurgeConfiguration::vec_as_string(&wireguard.head(), &wireguard.lines())
+      }
+    }
+  }
+
+  /// Writes the profile back following the parsed layout. Unchanged entries
+  /// keep their original text, changed ones are rendered canonically, new
+  /// ones go to the end of their section and sections that were not in the
+  /// original profile are appended.
+  fn layout_as_string(&self, layout: &[LayoutLine]) -> String {
+    let sections = self.sections();
+    let items: HashMap<_, _> = sections
+      .iter()
+      .map(|section| (*section, self.section_items(*section)))
+      .collect();
+    let parsed: HashSet<_> = layout
+      .iter()
+      .filter_map(|line| match line {
+        LayoutLine::Item { section, index, .. } => Some((*section, *index)),
+        _ => None,
+      })
+      .collect();
+    let mut finished = HashSet::new();
+    let mut lines: Vec<&str> = vec![];
+    for line in layout {
+      match line {
+        LayoutLine::Raw(raw) => lines.push(raw),
+        LayoutLine::Head => lines.push(&self.head),
+        LayoutLine::Item {
+          section,
+          index,
+          raw,
+          canonical,
+        } => match items[section].get(*index) {
+          Some(current) if current == canonical => lines.push(raw),
+          Some(current) if !current.is_empty() => lines.push(current),
+          _ => {}
+        },
+        LayoutLine::SectionEnd(section) => {
+          if finished.insert(*section) {
+            lines.extend(
+              items[section]
+                .iter()
+                .enumerate()
+                .filter(|(index, item)| !item.is_empty() && !parsed.contains(&(*section, *index)))
+                .map(|(_, item)| item.as_str()),
+            );
+          }
+        }
+      }
+    }
+    let mut ret = lines.join("\n");
+    for section in &sections {
+      if !finished.contains(section) && items[section].iter().any(|item| !item.is_empty()) {
+        if !ret.ends_with('\n') {
+          ret.push('\n');
+        }
+        ret.push('\n');
+        ret.push_str(&self.section_as_string(*section));
+      }
+    }
+    ret
   }
 
   fn vec_as_string<T: ToString>(head: &str, vec: &Vec<T>) -> String {
@@ -428,8 +1140,89 @@ impl SurgeConfiguration {
     SurgeConfiguration::vec_as_string("[URL Rewrite]", &self.url_rewrites)
   }
 
+  fn host_as_string(&self) -> String {
+    SurgeConfiguration::vec_as_string("[Host]", &self.hosts)
+  }
+
+  fn mitm_as_string(&self) -> String {
+    let lines: Vec<_> = self
+      .mitm
+      .lines()
+      .into_iter()
+      .filter(|line| !line.is_empty())
+      .collect();
+    SurgeConfiguration::vec_as_string("[MITM]", &lines)
+  }
+
+  fn script_as_string(&self) -> String {
+    SurgeConfiguration::vec_as_string("[Script]", &self.scripts)
+  }
+
+  /// Airport hostnames are unioned into ours, the rest of their `[MITM]`
+  /// settings is ignored. WireGuard proxies bring their section along.
   pub fn merge(&mut self, config: &SurgeConfiguration) {
-    self.proxies.append(&mut config.proxies.clone());
+    self.mitm.union_hostnames(&config.mitm);
+    for proxy in &config.proxies {
+      match proxy.proto {
+        ProxyProtocol::WireGuard => self.merge_wireguard_proxy(proxy, config),
+        _ => self.proxies.push(proxy.clone()),
+      }
+    }
+    for host in &config.hosts {
+      self.add_host(host.clone());
+    }
+    self.diagnostics.append(&mut config.diagnostics.clone());
+  }
+
+  /// Adds a WireGuard proxy of `config` together with the section it refers
+  /// to. Proxies whose section is missing are dropped.
+  fn merge_wireguard_proxy(&mut self, proxy: &Proxy, config: &SurgeConfiguration) {
+    let section_name = proxy.get_parameter("section-name").unwrap_or("");
+    match config
+      .wireguards
+      .iter()
+      .find(|wireguard| wireguard.get_name() == section_name)
+    {
+      Some(wireguard) => {
+        let section_name = self.add_wireguard_section(wireguard);
+        let mut proxy = proxy.clone();
+        proxy.set_parameter("section-name", &section_name);
+        self.proxies.push(proxy);
+      }
+      None => self.diagnostics.push(Diagnostic::new(
+        None,
+        Section::Proxy,
+        &proxy.to_string(),
+        format!("WireGuard section `{}` not found", section_name),
+      )),
+    }
+  }
+
+  pub fn set_wireguards(&mut self, wireguards: Vec<WireGuardSection>) {
+    self.wireguards = wireguards;
+  }
+
+  pub fn has_wireguard(&self, name: &str) -> bool {
+    self.wireguards.iter().any(|wireguard| wireguard.get_name() == name)
+  }
+
+  /// Adds a `[WireGuard <name>]` section and returns the name it ends up
+  /// with. A section with the same name and entries is shared, a different
+  /// one with the same name makes this one `<name> 2`, `<name> 3`, ...
+  fn add_wireguard_section(&mut self, wireguard: &WireGuardSection) -> String {
+    let mut name = String::from(wireguard.get_name());
+    let mut suffix = 1;
+    while let Some(existing) = self.wireguards.iter().find(|existing| existing.get_name() == name) {
+      if existing.same_entries(wireguard) {
+        return name;
+      }
+      suffix += 1;
+      name = format!("{} {}", wireguard.get_name(), suffix);
+    }
+    let mut wireguard = wireguard.clone();
+    wireguard.set_name(&name);
+    self.wireguards.push(wireguard);
+    name
   }
 
   pub fn set_head(&mut self, head: String) {
@@ -440,12 +1233,67 @@ impl SurgeConfiguration {
     self.general.push(general);
   }
 
+  /// Adds a proxy built by one of the subscription converters, rejecting it
+  /// if it is missing required parameters.
+  pub fn push_proxy(&mut self, proxy: Proxy) {
+    match proxy.validate() {
+      Ok(()) => {
+        self.report_unknown_parameters(None, &proxy.to_string(), &proxy);
+        self.proxies.push(proxy);
+      }
+      Err(reason) => self.diagnostics.push(Diagnostic::new(
+        None,
+        Section::Proxy,
+        &proxy.to_string(),
+        reason,
+      )),
+    }
+  }
+
   pub fn add_proxy(&mut self, proxy_str: &str) {
-    if let Some(proxy) = Proxy::from_str(proxy_str) {
-      self.proxies.push(proxy);
+    match Proxy::from_str(proxy_str) {
+      Ok(proxy) => {
+        self.report_unknown_parameters(None, proxy_str, &proxy);
+        self.proxies.push(proxy);
+      }
+      Err(reason) => self
+        .diagnostics
+        .push(Diagnostic::new(None, Section::Proxy, proxy_str, reason)),
+    }
+  }
+
+  /// Records a proxy a subscription converter could not translate.
+  pub fn report_rejected_proxy(&mut self, proxy: &str, reason: String) {
+    self
+      .diagnostics
+      .push(Diagnostic::new(None, Section::Proxy, proxy, reason));
+  }
+
+  /// Records a diagnostic for each parameter of an accepted proxy that
+  /// Surge does not document, as they are usually typos. The proxy keeps
+  /// them.
+  fn report_unknown_parameters(&mut self, line_number: Option<usize>, line: &str, proxy: &Proxy) {
+    for name in proxy.proto.unknown_parameters(&proxy.parameters) {
+      self.diagnostics.push(Diagnostic::new(
+        line_number,
+        Section::Proxy,
+        line,
+        format!("{} proxy has unknown parameter `{}`", proxy.proto.as_str(), name),
+      ));
+    }
+  }
+
+  /// Marks every proxy as coming from `airport_id`.
+  pub fn set_airport(&mut self, airport_id: &str) {
+    for proxy in &mut self.proxies {
+      proxy.airport = Some(String::from(airport_id));
     }
   }
 
+  pub fn get_diagnostics(&self) -> &Vec<Diagnostic> {
+    &self.diagnostics
+  }
+
   pub fn add_proxy_group(&mut self, proxy_group: ProxyGroup) {
     self.proxy_groups.push(proxy_group);
   }
@@ -454,36 +1302,154 @@ impl SurgeConfiguration {
     self.rules.push(rule);
   }
 
+  /// Points the rules sending traffic to `policy` at `new_policy`.
+  pub fn redirect_rules(&mut self, policy: &str, new_policy: &str) {
+    for existing in &mut self.rules {
+      if rule::policy(existing) == Some(policy) {
+        *existing = rule::with_policy(existing, new_policy);
+      }
+    }
+  }
+
+  /// Points the rules and groups sending traffic to `policy` at
+  /// `new_policy`.
+  pub fn rename_policy(&mut self, policy: &str, new_policy: &str) {
+    self.redirect_rules(policy, new_policy);
+    for proxy_group in &mut self.proxy_groups {
+      proxy_group.rename_proxy(policy, new_policy);
+    }
+  }
+
+  pub fn report_duplicate_proxy(&mut self, proxy: &Proxy, duplicate_of: &str) {
+    self
+      .metadata
+      .add_duplicate_proxy(proxy.get_name(), proxy.get_airport(), duplicate_of);
+  }
+
+  pub fn report_pseudo_node(&mut self, name: &str) {
+    self.metadata.add_pseudo_node(name);
+  }
+
+  pub fn report_name_collision(&mut self, name: &str) {
+    self.metadata.add_name_collision(name);
+  }
+
+  pub fn report_empty_group(&mut self, group: &str) {
+    self.metadata.add_empty_group(group);
+  }
+
+  pub fn get_metadata(&self) -> &RenderMetadata {
+    &self.metadata
+  }
+
   pub fn add_url_rewrite(&mut self, url_rewrite: String) {
     self.url_rewrites.push(url_rewrite);
   }
 
+  /// Adds a `[Host]` entry. An existing entry for the same domain is
+  /// replaced, so whatever is added last wins.
+  pub fn add_host(&mut self, host: String) {
+    match host_key(&host) {
+      Some(domain) => match self
+        .hosts
+        .iter()
+        .position(|existing| host_key(existing) == Some(domain))
+      {
+        Some(index) => self.hosts[index] = host,
+        None => self.hosts.push(host),
+      },
+      None => self.diagnostics.push(Diagnostic::new(
+        None,
+        Section::Host,
+        &host,
+        String::from("expected `domain = address`"),
+      )),
+    }
+  }
+
+  pub fn merge_mitm(&mut self, mitm: &Mitm) {
+    self.mitm.merge(mitm);
+  }
+
+  pub fn clear_mitm(&mut self) {
+    self.mitm = Mitm::default();
+  }
+
+  pub fn clear_hosts(&mut self) {
+    self.hosts.clear();
+  }
+
+  pub fn add_header_rewrite(&mut self, header_rewrite: String) {
+    self.header_rewrites.push(header_rewrite);
+  }
+
+  pub fn add_body_rewrite(&mut self, body_rewrite: String) {
+    self.body_rewrites.push(body_rewrite);
+  }
+
+  pub fn add_map_local(&mut self, map_local: String) {
+    self.map_locals.push(map_local);
+  }
+
+  pub fn add_script(&mut self, script_str: &str) {
+    match Script::from_str(script_str) {
+      Ok(script) => self.scripts.push(script),
+      Err(reason) => self
+        .diagnostics
+        .push(Diagnostic::new(None, Section::Script, script_str, reason)),
+    }
+  }
+
   pub fn get_proxies(&self) -> &Vec<Proxy> {
     &self.proxies
   }
 
-  #[cfg(test)]
+  pub fn get_proxies_mut(&mut self) -> &mut Vec<Proxy> {
+    &mut self.proxies
+  }
+
   pub fn get_proxy_groups(&self) -> &Vec<ProxyGroup> {
     &self.proxy_groups
   }
-  
+
+  pub fn get_rules(&self) -> &Vec<String> {
+    &self.rules
+  }
+
   #[cfg(test)]
   pub fn get_url_rewrites(&self) -> &Vec<String> {
     &self.url_rewrites
   }
+
+  #[cfg(test)]
+  pub fn get_hosts(&self) -> &Vec<String> {
+    &self.hosts
+  }
+
+  #[cfg(test)]
+  pub fn get_mitm(&self) -> &Mitm {
+    &self.mitm
+  }
 }
 
 impl ToString for SurgeConfiguration {
   fn to_string(&self) -> String {
-    [
-      &*self.head,
-      &*self.general_as_string(),
-      &*self.proxy_as_string(),
-      &*self.proxy_group_as_string(),
-      &*self.rule_as_string(),
-      &*self.url_rewrite_as_string(),
-    ]
-    .join("\n\n")
+    if let Some(layout) = &self.layout {
+      return self.layout_as_string(layout);
+    }
+    let mut sections = vec![
+      self.head.clone(),
+      self.general_as_string(),
+      self.proxy_as_string(),
+      self.proxy_group_as_string(),
+      self.rule_as_string(),
+    ];
+    for section in self.sections().iter().filter(|section| section.is_optional()) {
+      if !self.is_section_empty(*section) {
+        sections.push(self.section_as_string(*section));
+      }
+    }
+    sections.join("\n\n")
   }
 }
 
@@ -507,7 +1473,7 @@ mod test {
     )
     .expect("Parsing should work");
     assert_eq!(proxy.name, "🇨🇳 CN2 ⚡");
-    assert_eq!(proxy.proto, "https");
+    assert_eq!(proxy.proto, ProxyProtocol::Https);
     assert_eq!(proxy.port, 3389);
     assert_eq!(proxy.host, "a.b.c.net");
     assert_eq!(proxy.username.unwrap(), "username");
@@ -521,7 +1487,7 @@ mod test {
     let proxy = Proxy::from_str("🇭🇰 HK Standard A01 | Media | Rate 0.5x = ss, endpoint, 447, encrypt-method=abc, password=ddd, obfs=abc,obfs-host=ddd, tfo=true")
       .expect("Parsing should work");
     assert_eq!(proxy.name, "🇭🇰 HK Standard A01 | Media | Rate 0.5x");
-    assert_eq!(proxy.proto, "ss");
+    assert_eq!(proxy.proto, ProxyProtocol::Ss);
     assert_eq!(proxy.port, 447);
     assert_eq!(proxy.host, "endpoint");
     assert!(proxy.username.is_none());
@@ -533,6 +1499,113 @@ mod test {
     assert_eq!(proxy.parameters.get("tfo").unwrap(), "true");
   }
 
+  #[test]
+  pub fn quoted_proxy_round_trip_should_work() {
+    let proxy = Proxy::from_str(r#"🇯🇵 JP 01 = vmess, jp.example.com, 443, username=7c5c4a1e-0000-4000-8000-000000000000, ws=true, ws-path=/v2?ed=2048, ws-headers="Host:jp.example.com|User-Agent:Mozilla/5.0 (X11, Linux)", tls=true, sni=jp.example.com"#)
+      .expect("Parsing should work");
+    assert_eq!(proxy.parameters.get("ws-path").unwrap(), "/v2?ed=2048");
+    assert_eq!(
+      proxy.parameters.get("ws-headers").unwrap(),
+      "Host:jp.example.com|User-Agent:Mozilla/5.0 (X11, Linux)"
+    );
+    assert_eq!(
+      proxy.to_string(),
+      r#"🇯🇵 JP 01 = vmess,jp.example.com,443,sni=jp.example.com,tls=true,username=7c5c4a1e-0000-4000-8000-000000000000,ws=true,ws-headers="Host:jp.example.com|User-Agent:Mozilla/5.0 (X11, Linux)",ws-path=/v2?ed=2048"#
+    );
+
+    let proxy = Proxy::from_str(r#"Office = socks5-tls, a.com, 1080, user, "p@ss,w=rd", skip-cert-verify=true"#)
+      .expect("Parsing should work");
+    assert_eq!(proxy.password.as_deref(), Some("p@ss,w=rd"));
+    assert_eq!(
+      proxy.to_string(),
+      r#"Office = socks5-tls,a.com,1080,user,"p@ss,w=rd",skip-cert-verify=true"#
+    );
+  }
+
+  #[test]
+  pub fn wireguard_proxy_round_trip_should_work() {
+    let proxy = Proxy::from_str("WARP = wireguard, section-name=Cloudflare, test-url=http://a.com")
+      .expect("Parsing should work");
+    assert_eq!(proxy.proto, ProxyProtocol::WireGuard);
+    assert_eq!(
+      proxy.to_string(),
+      "WARP = wireguard,section-name=Cloudflare,test-url=http://a.com"
+    );
+  }
+
+  #[test]
+  pub fn invalid_proxies_should_be_rejected() {
+    let surge_config = SurgeConfiguration::from_config_string(
+      r#"[Proxy]
+DIRECT = direct
+A = ss, a.com, 443, encrypt-methd=abc, password=ddd
+B = vmess, b.com, 443, tls=true
+C = vless, c.com, 443
+D = trojan, d.com, 443, password=pwd, future-option=1"#,
+    )
+    .unwrap();
+    assert_eq!(surge_config.proxies.len(), 1);
+    assert_eq!(
+      surge_config.proxies[0].to_string(),
+      "D = trojan,d.com,443,future-option=1,password=pwd"
+    );
+    let reasons: Vec<_> = surge_config
+      .get_diagnostics()
+      .iter()
+      .map(|diagnostic| diagnostic.reason.as_str())
+      .collect();
+    assert_eq!(
+      reasons,
+      vec![
+        "ss proxy is missing required parameter `encrypt-method` (unknown parameters: `encrypt-methd`)",
+        "vmess proxy is missing required parameter `username`",
+        "unsupported protocol `vless`",
+        "trojan proxy has unknown parameter `future-option`",
+      ]
+    );
+  }
+
+  #[test]
+  pub fn diagnostics_should_have_line_numbers() {
+    let profile = r#"#!MANAGED-CONFIG https://abc.com
+
+[Proxy]
+A = trojan, a.com, 443, password=pwd
+B = trojan, b.com, port, password=pwd
+
+[Proxy Group]
+Proxy = select, A
+Broken
+Other = select-one, A
+"#;
+    let surge_config = SurgeConfiguration::from_config_string(profile).unwrap();
+    assert_eq!(surge_config.proxy_groups.len(), 1);
+    assert_eq!(surge_config.to_string(), profile);
+    assert_eq!(
+      surge_config.get_diagnostics(),
+      &vec![
+        Diagnostic {
+          line_number: Some(5),
+          section: "Proxy",
+          line: String::from("B = trojan, b.com, port, password=pwd"),
+          reason: String::from("invalid port `port`"),
+        },
+        Diagnostic {
+          line_number: Some(9),
+          section: "Proxy Group",
+          line: String::from("Broken"),
+          reason: String::from("expected `name = definition`"),
+        },
+        Diagnostic {
+          line_number: Some(10),
+          section: "Proxy Group",
+          line: String::from("Other = select-one, A"),
+          reason: String::from("unsupported group type `select-one`"),
+        },
+      ]
+    );
+  }
+
   #[test]
   pub fn https_proxy_to_string_should_work() {
     let mut params = BTreeMap::new();
@@ -540,12 +1613,13 @@ mod test {
     params.insert(String::from("abc"), String::from("def"));
     let proxy = Proxy {
       name: String::from("https proxy"),
-      proto: String::from("https"),
+      proto: ProxyProtocol::Https,
       host: String::from("www.a.com"),
       port: 447,
       username: Some(String::from("abc")),
       password: Some(String::from("def")),
       parameters: params,
+      airport: None,
     };
     assert_eq!(
       proxy.to_string(),
@@ -595,6 +1669,34 @@ mod test {
     )
   }
 
+  #[test]
+  pub fn other_group_types_should_round_trip() {
+    for group in &[
+      "Backup = fallback,HK 01,JP 01,url=http://a.com/204,interval=300,timeout=3",
+      "Balance = load-balance,HK 01,JP 01,url=http://a.com/204,interval=600,timeout=5,persistent=true",
+      "Network = subnet,default=Proxy,SSID:Home Wi-Fi=DIRECT,TYPE:CELLULAR=Backup",
+      "Pick = smart,HK01,JP01",
+      "Auto = url-test,HK01,url=http://a.com/204,interval=600,tolerance=100,timeout=5,hidden=true,no-alert=1",
+      "Home = subnet,default=Proxy,SSID:Home=DIRECT,hidden=true",
+    ] {
+      let proxy_group = ProxyGroup::from_str(group).expect("Parsing should work");
+      assert_eq!(&proxy_group.to_string(), group);
+    }
+    let proxy_group = ProxyGroup::from_str("Network = subnet, SSID:Home = DIRECT, default = Proxy").unwrap();
+    assert_eq!(
+      proxy_group.group_type,
+      ProxyGroupType::Subnet {
+        default: String::from("Proxy"),
+        mappings: vec![(String::from("SSID:Home"), String::from("DIRECT"))],
+      }
+    );
+    assert!(proxy_group.proxy_names.is_empty());
+    assert_eq!(
+      ProxyGroup::from_str("Network = subnet, SSID:Home = DIRECT").unwrap_err(),
+      "subnet group is missing `default`"
+    );
+  }
+
   #[test]
   pub fn to_config_should_work() {
     let mut surge_config = SurgeConfiguration::default();
@@ -636,6 +1738,195 @@ DOMAIN-SUFFIX,gazellegames.net,DIRECT
     );
   }
 
+  const PROFILE: &str = r#"#!MANAGED-CONFIG https://abc.com interval=86400 strict=false
+# Generated for someone
+
+[General]
+loglevel = notify
+skip-proxy = 127.0.0.1, 192.168.0.0/16
+; dns-server = 1.1.1.1
+
+[Proxy]
+DIRECT = direct
+🇭🇰 HK 01 = ss, hk.example.com, 443, encrypt-method=aes-128-gcm, password=pwd, obfs=http, obfs-host=a=b
+  // Backup
+🇯🇵 JP 01 = trojan, jp.example.com, 443, password=pwd, sni=jp.example.com
+
+[Proxy Group]
+Proxy = select, 🇭🇰 HK 01, 🇯🇵 JP 01
+Fallback = fallback, 🇭🇰 HK 01, 🇯🇵 JP 01, url=http://www.gstatic.com/generate_204
+
+[Host]
+example.com = 1.2.3.4
+*.local = server:syslib
+
+[Rule]
+# Streaming
+DOMAIN-SUFFIX,netflix.com,Proxy
+FINAL,DIRECT
+
+[MITM]
+hostname = *.example.com
+ca-passphrase = secret
+
+[WireGuard Home]
+private-key = abc=
+peer = (public-key = def=, endpoint = home.example.com:51820)
+"#;
+
+  #[test]
+  pub fn unchanged_profile_should_round_trip() {
+    let surge_config = SurgeConfiguration::from_config_string(PROFILE).unwrap();
+    assert_eq!(surge_config.general.len(), 2);
+    assert_eq!(surge_config.proxies.len(), 2);
+    assert_eq!(surge_config.proxy_groups.len(), 2);
+    assert_eq!(surge_config.rules.len(), 2);
+    assert_eq!(surge_config.to_string(), PROFILE);
+
+    let crlf = PROFILE.replace('\n', "\r\n");
+    let surge_config = SurgeConfiguration::from_config_string(&crlf).unwrap();
+    assert_eq!(surge_config.to_string(), PROFILE);
+  }
+
+  #[test]
+  pub fn edited_profile_should_keep_layout() {
+    let mut surge_config = SurgeConfiguration::from_config_string(PROFILE).unwrap();
+    surge_config.set_head(String::from("#!MANAGED-CONFIG https://new.com"));
+    surge_config.proxies[1].set_parameter("tfo", "true");
+    surge_config.add_proxy("🇺🇸 US 01 = trojan, us.example.com, 443, password=pwd");
+    surge_config.add_rule(String::from("GEOIP,CN,DIRECT"));
+    surge_config.add_url_rewrite(String::from("^https?://(www.)?g.cn https://www.google.com 302"));
+    let config = surge_config.to_string();
+    assert!(config.starts_with("#!MANAGED-CONFIG https://new.com\n# Generated for someone\n"));
+    assert!(config.contains(
+      r#"🇭🇰 HK 01 = ss, hk.example.com, 443, encrypt-method=aes-128-gcm, password=pwd, obfs=http, obfs-host=a=b
+  // Backup
+🇯🇵 JP 01 = trojan,jp.example.com,443,password=pwd,sni=jp.example.com,tfo=true
+🇺🇸 US 01 = trojan,us.example.com,443,password=pwd
+
+[Proxy Group]"#
+    ));
+    assert!(config.contains("DOMAIN-SUFFIX,netflix.com,Proxy\nFINAL,DIRECT\nGEOIP,CN,DIRECT\n\n[MITM]"));
+    assert!(config.ends_with(
+      "endpoint = home.example.com:51820)\n\n[URL Rewrite]\n^https?://(www.)?g.cn https://www.google.com 302"
+    ));
+  }
+
+  #[test]
+  pub fn hosts_should_merge_by_domain() {
+    let airport = SurgeConfiguration::from_config_string(
+      "[Host]\n*.example.com = server:1.1.1.1\nintranet.corp = 10.0.0.1\nbroken\n",
+    )
+    .unwrap();
+    assert_eq!(airport.get_hosts().len(), 2);
+    assert_eq!(airport.get_diagnostics()[0].line_number, Some(4));
+
+    let mut surge_config = SurgeConfiguration::default();
+    surge_config.merge(&airport);
+    surge_config.add_host(String::from("intranet.corp = 192.168.1.10"));
+    surge_config.add_host(String::from("nas.local = 192.168.1.2"));
+    assert_eq!(
+      surge_config.get_hosts(),
+      &vec![
+        "*.example.com = server:1.1.1.1",
+        "intranet.corp = 192.168.1.10",
+        "nas.local = 192.168.1.2",
+      ]
+    );
+    let profile = surge_config.to_string();
+    assert!(profile.ends_with(
+      "[Host]\n*.example.com = server:1.1.1.1\nintranet.corp = 192.168.1.10\nnas.local = 192.168.1.2"
+    ));
+    assert!(!profile.contains("[URL Rewrite]"));
+  }
+
+  #[test]
+  pub fn mitm_should_merge_and_keep_layout() {
+    let airport = SurgeConfiguration::from_config_string(
+      "[MITM]\nhostname = *.airport.com, api.example.com\nca-passphrase = airport\n",
+    )
+    .unwrap();
+    let mut user_mitm = Mitm::default();
+    user_mitm.set("hostname", "api.example.com, *.corp.com").unwrap();
+    user_mitm.set("ca-passphrase", "secret").unwrap();
+    let mut surge_config = SurgeConfiguration::default();
+    surge_config.merge(&airport);
+    surge_config.merge_mitm(&user_mitm);
+    assert!(surge_config.to_string().ends_with(
+      "[MITM]\nhostname = *.airport.com, api.example.com, *.corp.com\nca-passphrase = secret"
+    ));
+
+    let mut surge_config = SurgeConfiguration::from_config_string(PROFILE).unwrap();
+    assert_eq!(surge_config.get_mitm().get_hostnames(), &vec!["*.example.com"]);
+    let mut extra = Mitm::default();
+    extra.set("hostname", "*.corp.com").unwrap();
+    extra.set("skip-server-cert-verify", "true").unwrap();
+    surge_config.merge_mitm(&extra);
+    assert!(surge_config.to_string().contains(
+      "[MITM]\nhostname = *.example.com, *.corp.com\nca-passphrase = secret\nskip-server-cert-verify = true\n\n[WireGuard Home]"
+    ));
+  }
+
+  #[test]
+  pub fn wireguard_sections_should_be_renamed_on_merge() {
+    let airport = |peer: &str| {
+      SurgeConfiguration::from_config_string(&format!(
+        "[Proxy]\nWG = wireguard, section-name=Home\nLost = wireguard, section-name=Office\n\n[WireGuard Home]\nprivate-key = abc=\npeer = (public-key = def=, endpoint = {}:51820)\n",
+        peer
+      ))
+      .unwrap()
+    };
+    let mut surge_config = SurgeConfiguration::from_config_string(PROFILE).unwrap();
+    surge_config.merge(&airport("home.example.com"));
+    surge_config.merge(&airport("other.example.com"));
+    let section_names: Vec<_> = surge_config
+      .get_proxies()
+      .iter()
+      .filter_map(|proxy| proxy.get_parameter("section-name"))
+      .collect();
+    assert_eq!(section_names, vec!["Home", "Home 2"]);
+    assert_eq!(
+      surge_config.get_diagnostics().last().unwrap().reason,
+      "WireGuard section `Office` not found"
+    );
+    let profile = surge_config.to_string();
+    assert_eq!(profile.matches("[WireGuard Home]").count(), 1);
+    assert!(profile.ends_with(
+      "[WireGuard Home 2]\nprivate-key = abc=\npeer = (public-key = def=, endpoint = other.example.com:51820)"
+    ));
+  }
+
+  #[test]
+  pub fn rewrite_sections_should_work() {
+    let mut surge_config = SurgeConfiguration::from_config_string(
+      r#"[Header Rewrite]
+http-request ^https://api.example.com header-add X-Token abc
+^https://a.com header-set X-Token abc
+
+[Map Local]
+^https://a.com/ads data="{}" header="Content-Type:application/json"
+"#,
+    )
+    .unwrap();
+    assert_eq!(surge_config.header_rewrites.len(), 1);
+    assert_eq!(surge_config.get_diagnostics()[0].line_number, Some(3));
+    surge_config.add_body_rewrite(String::from(r#"http-response ^https://a.com/api "ads":\[.*?\] "ads":[]"#));
+    surge_config.add_map_local(String::from("^https://a.com/pixel data-type=tiny-gif"));
+    assert_eq!(
+      surge_config.to_string(),
+      r#"[Header Rewrite]
+http-request ^https://api.example.com header-add X-Token abc
+^https://a.com header-set X-Token abc
+
+[Map Local]
+^https://a.com/ads data="{}" header="Content-Type:application/json"
+^https://a.com/pixel data-type=tiny-gif
+
+[Body Rewrite]
+http-response ^https://a.com/api "ads":\[.*?\] "ads":[]"#
+    );
+  }
+
   #[tokio::test]
   pub async fn surge_config_from_string_should_work() {
     let surge_config = SurgeConfiguration::from_config_string(
@@ -667,6 +1958,20 @@ DOMAIN-SUFFIX,gazellegames.net,DIRECT
     assert_eq!(surge_config.url_rewrites.len(), 1);
   }
 
+  #[test]
+  pub fn surge_config_from_clash_text_should_work() {
+    let surge_config = SurgeConfiguration::from_text(
+      r#"proxies:
+  - { name: Proxy_1, type: trojan, server: a.com, port: 443, password: abc, sni: a.com }
+"#,
+    )
+    .unwrap();
+    assert_eq!(surge_config.proxies.len(), 1);
+    assert_eq!(surge_config.proxies[0].name, "Proxy_1");
+    assert_eq!(surge_config.proxies[0].get_proto(), "trojan");
+    assert_eq!(surge_config.proxies[0].parameters.get("password").unwrap(), "abc");
+  }
+
   #[tokio::test]
   pub async fn surge_config_from_url_should_work() {
     let surge_config = SurgeConfiguration::from_url("https://gist.githubusercontent.com/nearsyh/45695b3332f02609c71a1a084dbfb5bf/raw/67c0c6b1ae2c5a8f044a5f7ea10d009c990c5469/surge_config_airport_2")
@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+
+/// One comma separated field of a Surge definition, e.g. the `ss`, `a.com`
+/// and `password=abc` in `A = ss, a.com, 443, password=abc`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Field {
+  Positional(String),
+  Named(String, String),
+}
+
+/// Accumulates the characters of a key or value. Whitespace outside quotes
+/// is only kept when something other than whitespace follows it, which trims
+/// both ends without touching quoted content.
+#[derive(Default)]
+struct Buffer {
+  text: String,
+  pending: String,
+  started: bool,
+}
+
+impl Buffer {
+  fn push(&mut self, c: char, quoted: bool) {
+    if !quoted && c.is_whitespace() {
+      if self.started {
+        self.pending.push(c);
+      }
+    } else {
+      self.start();
+      self.text.push(c);
+    }
+  }
+
+  fn start(&mut self) {
+    self.text.push_str(&self.pending);
+    self.pending.clear();
+    self.started = true;
+  }
+
+  fn take(&mut self) -> String {
+    let text = std::mem::take(&mut self.text);
+    *self = Buffer::default();
+    text
+  }
+}
+
+/// Splits a definition on commas outside `"..."`. A field is named when it
+/// has an unquoted `=`; only the first one separates the key from the value.
+/// Inside quotes `\"` and `\\` are escapes, any other backslash is literal.
+pub fn tokenize(definition: &str) -> Vec<Field> {
+  let mut fields = vec![];
+  let mut key: Option<String> = None;
+  let mut buffer = Buffer::default();
+  let mut in_quotes = false;
+  let mut chars = definition.chars().peekable();
+  while let Some(c) = chars.next() {
+    match c {
+      '"' => {
+        buffer.start();
+        in_quotes = !in_quotes;
+      }
+      '\\' if in_quotes => match chars.peek() {
+        Some(next @ '"') | Some(next @ '\\') => {
+          buffer.push(*next, true);
+          chars.next();
+        }
+        _ => buffer.push(c, true),
+      },
+      ',' if !in_quotes => fields.push(field(key.take(), buffer.take())),
+      '=' if !in_quotes && key.is_none() => key = Some(buffer.take()),
+      _ => buffer.push(c, in_quotes),
+    }
+  }
+  fields.push(field(key, buffer.take()));
+  fields
+}
+
+fn field(key: Option<String>, value: String) -> Field {
+  match key {
+    Some(key) => Field::Named(key, value),
+    None => Field::Positional(value),
+  }
+}
+
+/// The positional fields, in order.
+pub fn positional(fields: &[Field]) -> Vec<String> {
+  fields
+    .iter()
+    .filter_map(|field| match field {
+      Field::Positional(value) => Some(value.clone()),
+      _ => None,
+    })
+    .collect()
+}
+
+/// The named fields. A repeated key keeps its last value.
+pub fn named(fields: &[Field]) -> BTreeMap<String, String> {
+  fields
+    .iter()
+    .filter_map(|field| match field {
+      Field::Named(key, value) => Some((key.clone(), value.clone())),
+      _ => None,
+    })
+    .collect()
+}
+
+fn quote_if(value: &str, needs_quotes: bool) -> String {
+  if needs_quotes || value.trim() != value || value.contains([',', '"']) {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+  } else {
+    String::from(value)
+  }
+}
+
+/// Renders a named field value so that `tokenize` reads it back unchanged.
+pub fn quote(value: &str) -> String {
+  quote_if(value, false)
+}
+
+/// Like `quote`, but also quotes a `=` which would otherwise make the field
+/// named.
+pub fn quote_positional(value: &str) -> String {
+  quote_if(value, value.contains('='))
+}
+
+/// Like `quote`, but also quotes inner whitespace, the way Surge writes
+/// script parameters such as `cronexp="0 8 * * *"`.
+pub fn quote_whitespace(value: &str) -> String {
+  quote_if(value, value.contains(char::is_whitespace))
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::*;
+
+  fn positional_field(value: &str) -> Field {
+    Field::Positional(String::from(value))
+  }
+
+  fn named_field(key: &str, value: &str) -> Field {
+    Field::Named(String::from(key), String::from(value))
+  }
+
+  #[test]
+  pub fn tokenize_should_work() {
+    assert_eq!(
+      tokenize(" ss , a.com,443 , encrypt-method = aes-128-gcm,password=YWJj==, obfs-host=a=b, tfo=true"),
+      vec![
+        positional_field("ss"),
+        positional_field("a.com"),
+        positional_field("443"),
+        named_field("encrypt-method", "aes-128-gcm"),
+        named_field("password", "YWJj=="),
+        named_field("obfs-host", "a=b"),
+        named_field("tfo", "true"),
+      ]
+    );
+  }
+
+  #[test]
+  pub fn quoted_values_should_work() {
+    assert_eq!(
+      tokenize(r#"vmess, a.com, 443, username=id, ws-headers="Host:a.com|User-Agent:Mozilla/5.0 (X11, Linux)", ws-path = " /a b ", policy-regex-filter=(\d+)"#),
+      vec![
+        positional_field("vmess"),
+        positional_field("a.com"),
+        positional_field("443"),
+        named_field("username", "id"),
+        named_field("ws-headers", "Host:a.com|User-Agent:Mozilla/5.0 (X11, Linux)"),
+        named_field("ws-path", " /a b "),
+        named_field("policy-regex-filter", r"(\d+)"),
+      ]
+    );
+    assert_eq!(
+      tokenize(r#"https, a.com, 443, "user=1", "pass\"word\\", "C:\dir""#),
+      vec![
+        positional_field("https"),
+        positional_field("a.com"),
+        positional_field("443"),
+        positional_field("user=1"),
+        positional_field(r#"pass"word\"#),
+        positional_field(r"C:\dir"),
+      ]
+    );
+  }
+
+  #[test]
+  pub fn group_definition_should_work() {
+    let fields = tokenize("url-test, 🇭🇰 HK A01 | Media, Proxy, url = http://www.qualcomm.cn/generate_204, interval = 1800");
+    assert_eq!(positional(&fields), vec!["url-test", "🇭🇰 HK A01 | Media", "Proxy"]);
+    let named = named(&fields);
+    assert_eq!(named.get("url").unwrap(), "http://www.qualcomm.cn/generate_204");
+    assert_eq!(named.get("interval").unwrap(), "1800");
+  }
+
+  #[test]
+  pub fn quote_should_round_trip() {
+    for value in &["plain", "a=b", "a,b", r#"say "hi""#, r"back\slash", " padded ", ""] {
+      let definition = format!("{},k={}", quote_positional(value), quote(value));
+      assert_eq!(
+        tokenize(&definition),
+        vec![positional_field(value), named_field("k", value)]
+      );
+    }
+    assert_eq!(quote("a=b"), "a=b");
+    assert_eq!(quote_positional("a=b"), "\"a=b\"");
+    assert_eq!(quote("0 8 * * *"), "0 8 * * *");
+    assert_eq!(quote_whitespace("0 8 * * *"), "\"0 8 * * *\"");
+  }
+}
@@ -0,0 +1,140 @@
+const HEAD_PREFIX: &str = "[WireGuard ";
+
+/// A `[WireGuard <name>]` section. WireGuard proxies refer to it through
+/// their `section-name` parameter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WireGuardSection {
+  name: String,
+  entries: Vec<(String, String)>,
+}
+
+impl WireGuardSection {
+  /// Starts an empty section from a head such as `[WireGuard Home]`.
+  pub fn from_head(line: &str) -> Option<WireGuardSection> {
+    line
+      .strip_prefix(HEAD_PREFIX)
+      .and_then(|rest| rest.strip_suffix(']'))
+      .map(|name| name.trim())
+      .filter(|name| !name.is_empty())
+      .map(|name| WireGuardSection {
+        name: String::from(name),
+        entries: vec![],
+      })
+  }
+
+  pub fn get_name(&self) -> &str {
+    &self.name
+  }
+
+  pub fn set_name(&mut self, name: &str) {
+    self.name = String::from(name);
+  }
+
+  /// Whether both sections hold the same keys and peers, whatever their name.
+  pub fn same_entries(&self, other: &WireGuardSection) -> bool {
+    self.entries == other.entries
+  }
+
+  /// Adds a `key = value` line, returning its index in `lines`.
+  pub fn push_entry(&mut self, line: &str) -> Result<usize, String> {
+    match line.split_once('=') {
+      Some((key, value)) if !key.trim().is_empty() => {
+        self
+          .entries
+          .push((String::from(key.trim()), String::from(value.trim())));
+        Ok(self.entries.len() - 1)
+      }
+      _ => Err(String::from("expected `key = value`")),
+    }
+  }
+
+  pub fn head(&self) -> String {
+    format!("{}{}]", HEAD_PREFIX, self.name)
+  }
+
+  pub fn lines(&self) -> Vec<String> {
+    self
+      .entries
+      .iter()
+      .map(|(key, value)| format!("{} = {}", key, value))
+      .collect()
+  }
+}
+
+/// Parses a text made only of `[WireGuard <name>]` sections, naming the
+/// first line that is not part of one.
+pub fn parse_sections(text: &str) -> Result<Vec<WireGuardSection>, String> {
+  let mut sections: Vec<WireGuardSection> = vec![];
+  for (line_number, line) in text.split('\n').enumerate() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let result = match WireGuardSection::from_head(line) {
+      Some(section) if sections.iter().any(|existing| existing.name == section.name) => {
+        Err(format!("duplicate section `{}`", section.name))
+      }
+      Some(section) => {
+        sections.push(section);
+        Ok(())
+      }
+      None => match sections.last_mut() {
+        Some(section) => section.push_entry(line).map(|_| ()),
+        None => Err(String::from("expected a `[WireGuard <name>]` section")),
+      },
+    };
+    result.map_err(|reason| format!("line {}: {}", line_number + 1, reason))?;
+  }
+  Ok(sections)
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::*;
+
+  #[test]
+  pub fn wireguard_section_should_work() {
+    let mut section = WireGuardSection::from_head("[WireGuard Home Lab]").unwrap();
+    assert_eq!(section.get_name(), "Home Lab");
+    assert_eq!(section.push_entry("private-key = abc="), Ok(0));
+    assert_eq!(
+      section.push_entry("peer = (public-key = def=, allowed-ips = \"0.0.0.0/0, ::/0\", endpoint = a.com:51820)"),
+      Ok(1)
+    );
+    assert!(section.push_entry("mtu").is_err());
+    assert_eq!(section.head(), "[WireGuard Home Lab]");
+    assert_eq!(
+      section.lines(),
+      vec![
+        "private-key = abc=",
+        "peer = (public-key = def=, allowed-ips = \"0.0.0.0/0, ::/0\", endpoint = a.com:51820)",
+      ]
+    );
+    assert!(WireGuardSection::from_head("[WireGuard]").is_none());
+    assert!(WireGuardSection::from_head("[Proxy]").is_none());
+  }
+
+  #[test]
+  pub fn parse_sections_should_work() {
+    let sections = parse_sections(
+      "[WireGuard Home]\nprivate-key = abc=\n\n# Office\n[WireGuard Office]\nprivate-key = def=\n",
+    )
+    .unwrap();
+    let names: Vec<_> = sections.iter().map(|section| section.get_name()).collect();
+    assert_eq!(names, vec!["Home", "Office"]);
+    assert_eq!(sections[1].lines(), vec!["private-key = def="]);
+    assert_eq!(
+      parse_sections("private-key = abc="),
+      Err(String::from("line 1: expected a `[WireGuard <name>]` section"))
+    );
+    assert_eq!(
+      parse_sections("[WireGuard Home]\nmtu"),
+      Err(String::from("line 2: expected `key = value`"))
+    );
+    assert_eq!(
+      parse_sections("[WireGuard Home]\n[WireGuard Home]"),
+      Err(String::from("line 2: duplicate section `Home`"))
+    );
+  }
+}